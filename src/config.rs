@@ -0,0 +1,692 @@
+use log::debug;
+use regex::{Regex, RegexSet};
+use rhai::AST;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::desktop_entry::{split_list, DesktopEntry, MAIN_GROUP};
+use crate::discovery::{DesktopFile, Kind, Origin};
+use crate::spec::Severity;
+use crate::subprocess::Limits;
+
+/// Time a generator command may run if its `timeout` is not set.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub version: String,
+    /// What to do when a generator without its own `on-error` fails.
+    #[serde(default, rename = "on-error")]
+    pub on_error: Option<OnError>,
+    /// How strictly generated files are checked against the specification.
+    #[serde(default)]
+    pub validation: Option<Validation>,
+    pub generators: Vec<Generator>,
+}
+
+/// How strictly the output of generators is checked against the Desktop
+/// Entry Specification. A generator whose output has a problem that its
+/// input had not fails if the problem is blocking at this level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Validation {
+    /// Do not check.
+    Off,
+    /// Log every problem, none is blocking.
+    Warn,
+    /// Errors are blocking, warnings are logged.
+    #[default]
+    Error,
+    /// Errors and warnings are blocking.
+    Strict,
+}
+
+impl Validation {
+    /// Whether a problem of `severity` fails the generator introducing it.
+    pub fn is_blocking(self, severity: Severity) -> bool {
+        match self {
+            Validation::Off | Validation::Warn => false,
+            Validation::Error => severity == Severity::Error,
+            Validation::Strict => true,
+        }
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Validation::Off => "off",
+            Validation::Warn => "warn",
+            Validation::Error => "error",
+            Validation::Strict => "strict",
+        })
+    }
+}
+
+/// What to do when a generator fails, e.g. exits non-zero or times out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnError {
+    /// Ignore the output of the generator and go on with the next one.
+    #[default]
+    Skip,
+    /// Leave the file as it is and go on with the next file.
+    AbortFile,
+    /// Leave the file as it is and stop the run.
+    AbortAll,
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OnError::Skip => "skip",
+            OnError::AbortFile => "abort-file",
+            OnError::AbortAll => "abort-all",
+        })
+    }
+}
+
+/// How a generator command gets the desktop file and gives back the result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// The file as is on stdin, the new file on stdout.
+    #[default]
+    Text,
+    /// The parsed entry as JSON on stdin, a JSON entry or a list of edits on
+    /// stdout.
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Generator {
+    /// Kind of files the generator applies to, applications by default.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub kind: Kind,
+    /// Regex matched against the desktop file ID, e.g. `kde4-foo.desktop`.
+    #[serde(default = "default_filter")]
+    pub filter: String,
+    /// Condition on the content of desktop files, checked in addition to
+    /// `filter`.
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+    pub name: String,
+    /// Remove the generator of this name defined by a config file with
+    /// lower precedence.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Config file defining this generator.
+    #[serde(skip)]
+    pub origin: PathBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// How the desktop file is passed to `command` and read back.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub protocol: Protocol,
+    /// Rhai script run on the parsed entry after the actions and before
+    /// `command`, without spawning any process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// Seconds `command` or `script` may run before it is stopped, 60 by
+    /// default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+    /// Bytes `command` may write to stdout or stderr before it is killed.
+    #[serde(
+        default,
+        rename = "max-output",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_output: Option<usize>,
+    /// What to do when this generator fails, overriding the global
+    /// `on-error`.
+    #[serde(default, rename = "on-error", skip_serializing_if = "Option::is_none")]
+    pub on_error: Option<OnError>,
+    /// Group the declarative actions below apply to.
+    #[serde(default = "default_group", skip_serializing_if = "is_default_group")]
+    pub group: String,
+    /// Keys to rename, applied first.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+    /// Keys to set to the given raw value, applied after `rename`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+    /// Keys to remove, applied after `set`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unset: Vec<String>,
+}
+
+/// A condition on a desktop file.
+///
+/// ```yaml
+/// match:
+///   all:
+///     - { key: Categories, contains: Game }
+///     - not: { filename: '^steam' }
+///     - not: { origin: snap }
+///     - any:
+///         - { key: Exec, matches: '^flatpak run' }
+///         - { group: Desktop Action new-window }
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Condition {
+    All { all: Vec<Condition> },
+    Any { any: Vec<Condition> },
+    Not { not: Box<Condition> },
+    Filename { filename: String },
+    Key(KeyCondition),
+    Group { group: String },
+    Origin { origin: Origin },
+}
+
+/// Condition on a single key. Every given check has to hold; with none
+/// given, the key only has to be present.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyCondition {
+    /// Key to check, may carry a locale suffix like `Name[de]`.
+    pub key: String,
+    #[serde(default = "default_group", skip_serializing_if = "is_default_group")]
+    pub group: String,
+    /// The unescaped value equals this string.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<String>,
+    /// The unescaped value matches this regex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matches: Option<String>,
+    /// The value is a `;` separated list, like `MimeType` or `Categories`,
+    /// containing this element.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains: Option<String>,
+    /// Whether the key is present, `true` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub present: Option<bool>,
+}
+
+pub fn default_filter() -> String {
+    ".*".to_string()
+}
+
+pub fn default_group() -> String {
+    MAIN_GROUP.to_string()
+}
+
+fn is_default_group(group: &str) -> bool {
+    group == MAIN_GROUP
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl Config {
+    /// Merge `configs`, given from the lowest precedence to the highest. A
+    /// generator replaces the one of the same name defined before it, keeping
+    /// its position. Disabled generators are dropped. The last `on-error`
+    /// and `validation` given win.
+    pub fn merge(configs: Vec<Config>) -> Config {
+        let mut generators: Vec<Generator> = Vec::new();
+        let mut on_error = None;
+        let mut validation = None;
+        for config in configs {
+            on_error = config.on_error.or(on_error);
+            validation = config.validation.or(validation);
+            for generator in config.generators {
+                match generators.iter_mut().find(|g| g.name == generator.name) {
+                    Some(existing) => {
+                        debug!(
+                            "Generator {} of {:?} overrides the one of {:?}",
+                            generator.name, generator.origin, existing.origin
+                        );
+                        *existing = generator;
+                    }
+                    None => generators.push(generator),
+                }
+            }
+        }
+        generators.retain(|generator| !generator.disabled);
+
+        Config {
+            version: crate::migrate::CONFIG_VERSION.to_string(),
+            on_error,
+            validation,
+            generators,
+        }
+    }
+}
+
+impl Config {
+    /// Serialize the config as YAML, preceding every generator with a
+    /// comment naming the file it was defined in.
+    pub fn to_annotated_yaml(&self) -> String {
+        let mut yaml = format!("version: {}\n", self.version);
+        if let Some(on_error) = self.on_error {
+            yaml.push_str(&format!("on-error: {}\n", on_error));
+        }
+        if let Some(validation) = self.validation {
+            yaml.push_str(&format!("validation: {}\n", validation));
+        }
+        if self.generators.is_empty() {
+            yaml.push_str("generators: []\n");
+            return yaml;
+        }
+
+        yaml.push_str("generators:\n");
+        for generator in &self.generators {
+            yaml.push_str(&format!("  # {}\n", generator.origin.display()));
+            let serialized = serde_yaml::to_string(generator).unwrap();
+            let serialized = serialized.strip_prefix("---\n").unwrap_or(&serialized);
+            for (index, line) in serialized.lines().enumerate() {
+                let indent = if index == 0 { "  - " } else { "    " };
+                yaml.push_str(&format!("{}{}\n", indent, line));
+            }
+        }
+        yaml
+    }
+}
+
+impl Generator {
+    pub fn has_actions(&self) -> bool {
+        !self.rename.is_empty() || !self.set.is_empty() || !self.unset.is_empty()
+    }
+
+    /// What to do when this generator fails.
+    pub fn on_error(&self, config: &Config) -> OnError {
+        self.on_error.or(config.on_error).unwrap_or_default()
+    }
+
+    /// Limits on the process running `command`.
+    pub fn limits(&self) -> Limits {
+        let timeout = self
+            .timeout
+            .and_then(|timeout| Duration::try_from_secs_f64(timeout).ok())
+            .unwrap_or(DEFAULT_TIMEOUT);
+        Limits {
+            timeout: Some(timeout),
+            max_output: self.max_output,
+        }
+    }
+}
+
+/// A config with the filters of all generators compiled into one set, so
+/// that a single scan of a desktop file ID finds every generator whose
+/// filter matches it, and with the regexes of conditions and the scripts
+/// compiled.
+#[derive(Debug)]
+pub struct CompiledConfig {
+    pub config: Config,
+    filters: RegexSet,
+    /// Compiled regexes of `match` conditions by pattern.
+    patterns: HashMap<String, Regex>,
+    /// Compiled scripts by generator name.
+    scripts: HashMap<String, AST>,
+}
+
+impl CompiledConfig {
+    pub fn new(config: Config) -> io::Result<CompiledConfig> {
+        let filters = RegexSet::new(config.generators.iter().map(|g| &g.filter))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut patterns = HashMap::new();
+        let mut scripts = HashMap::new();
+        for generator in &config.generators {
+            if let Some(condition) = &generator.condition {
+                condition.compile(&mut patterns).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Generator {}: {}", generator.name, e))
+                })?;
+            }
+            if let Some(source) = &generator.script {
+                let script = crate::script::compile(source).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Generator {}: {}", generator.name, e))
+                })?;
+                scripts.insert(generator.name.clone(), script);
+            }
+        }
+        Ok(CompiledConfig {
+            config,
+            filters,
+            patterns,
+            scripts,
+        })
+    }
+
+    /// Whether the `match` condition of `generator`, if it has one, holds for
+    /// `desktop_file`.
+    pub fn condition_holds(
+        &self,
+        generator: &Generator,
+        desktop_file: &DesktopFile,
+        entry: Option<&DesktopEntry>,
+    ) -> bool {
+        generator
+            .condition
+            .as_ref()
+            .is_none_or(|condition| condition.evaluate(&self.patterns, desktop_file, entry))
+    }
+
+    /// The compiled script of `generator`, if it has one.
+    pub fn script(&self, generator: &Generator) -> Option<&AST> {
+        self.scripts.get(&generator.name)
+    }
+
+    /// The generators of `kind` whose filter matches `id`, in the order they
+    /// are configured.
+    pub fn matching_filters<'a>(
+        &'a self,
+        kind: Kind,
+        id: &str,
+    ) -> impl Iterator<Item = &'a Generator> + 'a {
+        self.filters
+            .matches(id)
+            .into_iter()
+            .map(|index| &self.config.generators[index])
+            .filter(move |generator| generator.kind == kind)
+    }
+}
+
+impl Condition {
+    /// Compile the regexes of the condition missing from `patterns`.
+    fn compile(&self, patterns: &mut HashMap<String, Regex>) -> io::Result<()> {
+        let pattern = match self {
+            Condition::All { all: conditions } | Condition::Any { any: conditions } => {
+                for condition in conditions {
+                    condition.compile(patterns)?;
+                }
+                return Ok(());
+            }
+            Condition::Not { not } => return not.compile(patterns),
+            Condition::Filename { filename } => filename,
+            Condition::Key(KeyCondition {
+                matches: Some(matches),
+                ..
+            }) => matches,
+            _ => return Ok(()),
+        };
+        if !patterns.contains_key(pattern) {
+            let regex = Regex::new(pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            patterns.insert(pattern.clone(), regex);
+        }
+        Ok(())
+    }
+
+    /// Evaluate the condition on `desktop_file`, with its regexes compiled
+    /// into `patterns`. `entry` is `None` if the file could not be parsed, in
+    /// which case conditions on its content do not hold.
+    fn evaluate(
+        &self,
+        patterns: &HashMap<String, Regex>,
+        desktop_file: &DesktopFile,
+        entry: Option<&DesktopEntry>,
+    ) -> bool {
+        match self {
+            Condition::All { all } => all
+                .iter()
+                .all(|condition| condition.evaluate(patterns, desktop_file, entry)),
+            Condition::Any { any } => any
+                .iter()
+                .any(|condition| condition.evaluate(patterns, desktop_file, entry)),
+            Condition::Not { not } => !not.evaluate(patterns, desktop_file, entry),
+            Condition::Filename { filename } => patterns[filename].is_match(&desktop_file.id),
+            Condition::Key(condition) => condition.evaluate(patterns, entry),
+            Condition::Group { group } => entry.is_some_and(|entry| entry.group(group).is_some()),
+            Condition::Origin { origin } => desktop_file.origin == *origin,
+        }
+    }
+}
+
+impl KeyCondition {
+    fn evaluate(&self, patterns: &HashMap<String, Regex>, entry: Option<&DesktopEntry>) -> bool {
+        let value = entry
+            .and_then(|entry| entry.group(&self.group))
+            .and_then(|group| group.get(&self.key));
+
+        let Some(value) = value else {
+            return self.present == Some(false);
+        };
+        if self.present == Some(false) {
+            return false;
+        }
+
+        let unescaped = crate::desktop_entry::unescape(value);
+        if let Some(equals) = &self.equals {
+            if &unescaped != equals {
+                return false;
+            }
+        }
+        if let Some(matches) = &self.matches {
+            if !patterns[matches].is_match(&unescaped) {
+                return false;
+            }
+        }
+        if let Some(contains) = &self.contains {
+            if !split_list(value).contains(contains) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = "[Desktop Entry]\n\
+Name=Steam\n\
+Exec=flatpak run com.valvesoftware.Steam\n\
+Categories=Network;Game;\n\
+MimeType=x-scheme-handler/steam;\n\
+\n\
+[Desktop Action library]\n\
+Name=Library\n";
+
+    fn desktop_file(id: &str) -> DesktopFile {
+        DesktopFile {
+            id: id.to_string(),
+            path: PathBuf::from("/var/lib/flatpak/exports/share/applications").join(id),
+            data_dir: PathBuf::from("/var/lib/flatpak/exports/share"),
+            kind: Kind::Application,
+            origin: Origin::Flatpak,
+        }
+    }
+
+    fn evaluate_on(condition: &str, id: &str, entry: Option<&DesktopEntry>) -> bool {
+        let condition: Condition = serde_yaml::from_str(condition).unwrap();
+        let mut patterns = HashMap::new();
+        condition.compile(&mut patterns).unwrap();
+        condition.evaluate(&patterns, &desktop_file(id), entry)
+    }
+
+    fn evaluate(condition: &str) -> bool {
+        let entry = DesktopEntry::parse(ENTRY).unwrap();
+        evaluate_on(condition, "com.valvesoftware.Steam.desktop", Some(&entry))
+    }
+
+    #[test]
+    fn test_key_conditions() {
+        assert!(evaluate("{key: Name, equals: Steam}"));
+        assert!(!evaluate("{key: Name, equals: steam}"));
+        assert!(evaluate("{key: Exec, matches: '^flatpak run'}"));
+        assert!(evaluate("{key: Categories, contains: Game}"));
+        assert!(!evaluate("{key: Categories, contains: Gam}"));
+        assert!(evaluate(
+            "{key: MimeType, contains: x-scheme-handler/steam}"
+        ));
+        assert!(evaluate("{key: Name}"));
+        assert!(evaluate("{key: Terminal, present: false}"));
+        assert!(!evaluate("{key: Terminal}"));
+        assert!(evaluate(
+            "{key: Name, group: Desktop Action library, equals: Library}"
+        ));
+    }
+
+    #[test]
+    fn test_combined_conditions() {
+        assert!(evaluate("{group: Desktop Action library}"));
+        assert!(!evaluate("{group: Desktop Action missing}"));
+        assert!(evaluate("{filename: '^com\\.valvesoftware'}"));
+        assert!(evaluate(
+            "all: [{key: Categories, contains: Game}, {not: {filename: '^steam'}}]"
+        ));
+        assert!(!evaluate("all: [{key: Name}, {key: Missing}]"));
+        assert!(evaluate("any: [{key: Missing}, {key: Name}]"));
+        assert!(!evaluate("not: {key: Name}"));
+        assert!(evaluate("{origin: flatpak}"));
+        assert!(!evaluate("{origin: snap}"));
+    }
+
+    #[test]
+    fn test_unparsable_entry() {
+        assert!(evaluate_on(
+            "{key: Name, present: false}",
+            "broken.desktop",
+            None
+        ));
+        assert!(!evaluate_on("{key: Name}", "broken.desktop", None));
+    }
+
+    #[test]
+    fn test_merge() {
+        let load = |origin: &str, content: &str| {
+            let mut config: Config = serde_yaml::from_str(content).unwrap();
+            for generator in &mut config.generators {
+                generator.origin = PathBuf::from(origin);
+            }
+            config
+        };
+        let system = load(
+            "/etc/xdg",
+            "version: 0.2.0
+on-error: abort-all
+validation: strict
+generators:
+  - { name: a, unset: [A] }
+  - { name: b, unset: [B] }
+  - { name: c, unset: [C] }
+",
+        );
+        let user = load(
+            "~/.config",
+            "version: 0.2.0
+generators:
+  - { name: d, unset: [D] }
+  - { name: b, unset: [X] }
+  - { name: c, disabled: true }
+",
+        );
+
+        let merged = Config::merge(vec![system, user]);
+        assert_eq!(merged.on_error, Some(OnError::AbortAll));
+        assert_eq!(merged.validation, Some(Validation::Strict));
+        let generators: Vec<_> = merged
+            .generators
+            .iter()
+            .map(|g| {
+                (
+                    g.name.as_str(),
+                    g.origin.to_str().unwrap(),
+                    g.unset[0].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            generators,
+            [
+                ("a", "/etc/xdg", "A"),
+                ("b", "~/.config", "X"),
+                ("d", "~/.config", "D"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_annotated_yaml() {
+        let mut config: Config = serde_yaml::from_str(
+            "version: 0.2.0
+generators:
+  - { name: a, unset: [A], match: { key: Name } }
+  - { name: b, group: Desktop Action new, set: { Name: B } }
+",
+        )
+        .unwrap();
+        config.generators[0].origin = PathBuf::from("/etc/xdg/config.yaml");
+        config.generators[1].origin = PathBuf::from("/etc/xdg/config.d/b.yaml");
+
+        let yaml = config.to_annotated_yaml();
+        assert_eq!(
+            yaml,
+            "version: 0.2.0
+generators:
+  # /etc/xdg/config.yaml
+  - filter: \".*\"
+    match:
+      key: Name
+    name: a
+    unset:
+      - A
+  # /etc/xdg/config.d/b.yaml
+  - filter: \".*\"
+    name: b
+    group: Desktop Action new
+    set:
+      Name: B
+"
+        );
+
+        let reparsed: Config = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(reparsed.generators.len(), 2);
+        assert_eq!(reparsed.generators[0].unset, ["A"]);
+        assert_eq!(reparsed.generators[1].group, "Desktop Action new");
+    }
+
+    #[test]
+    fn test_compiled_config() {
+        let config: Config = serde_yaml::from_str(
+            "version: 0.2.0
+generators:
+  - { name: all, unset: [A] }
+  - { name: steam, filter: 'Steam', unset: [A] }
+  - { name: autostart, kind: autostart, filter: 'Steam', unset: [A] }
+  - { name: zed, filter: '^zed', unset: [A] }
+",
+        )
+        .unwrap();
+        let compiled = CompiledConfig::new(config).unwrap();
+        let names = |kind, id| {
+            compiled
+                .matching_filters(kind, id)
+                .map(|g| g.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(Kind::Application, "com.valvesoftware.Steam.desktop"),
+            ["all", "steam"]
+        );
+        assert_eq!(
+            names(Kind::Autostart, "com.valvesoftware.Steam.desktop"),
+            ["autostart"]
+        );
+        assert_eq!(names(Kind::Application, "zed.desktop"), ["all", "zed"]);
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        let config: Config = serde_yaml::from_str(
+            "version: 0.2.0
+generators:
+  - { name: a, match: { not: { key: Name, matches: '(' } }, unset: [A] }
+",
+        )
+        .unwrap();
+        let err = CompiledConfig::new(config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("Generator a: "));
+    }
+}
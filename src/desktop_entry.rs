@@ -0,0 +1,605 @@
+use std::fmt;
+use std::io;
+
+/// Name of the group every desktop file starts with, holding its main keys.
+pub const MAIN_GROUP: &str = "Desktop Entry";
+
+/// A parsed desktop entry file.
+///
+/// The parser keeps the raw text of every line it has not been asked to
+/// change, so serializing an unmodified entry reproduces the original input
+/// byte-for-byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopEntry {
+    /// Comments and blank lines before the first group header.
+    preamble: Vec<Line>,
+    groups: Vec<Group>,
+    trailing_newline: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    name: String,
+    header: String,
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Blank(String),
+    Comment(String),
+    Entry(Entry),
+}
+
+/// A `key=value` line. `key` includes the locale suffix, e.g. `Name[de]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    key: String,
+    value: String,
+    raw: Option<String>,
+}
+
+/// Why a desktop entry could not be parsed, the inner error of the
+/// `io::Error` returned by [`DesktopEntry::parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Number of the offending line, starting at 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl DesktopEntry {
+    pub fn parse(content: &str) -> io::Result<DesktopEntry> {
+        let mut preamble = Vec::new();
+        let mut groups: Vec<Group> = Vec::new();
+
+        let trailing_newline = content.ends_with('\n');
+        let body = content.strip_suffix('\n').unwrap_or(content);
+
+        if !body.is_empty() || trailing_newline {
+            for (index, raw) in body.split('\n').enumerate() {
+                let line = raw.strip_suffix('\r').unwrap_or(raw);
+                let trimmed = line.trim_start();
+
+                let parsed = if trimmed.is_empty() {
+                    Line::Blank(raw.to_string())
+                } else if trimmed.starts_with('#') {
+                    Line::Comment(raw.to_string())
+                } else if line.starts_with('[') {
+                    let name = parse_group_header(line)
+                        .ok_or_else(|| parse_error(index, "malformed group header"))?;
+                    if groups.iter().any(|group| group.name == name) {
+                        return Err(parse_error(index, &format!("duplicate group [{}]", name)));
+                    }
+                    groups.push(Group {
+                        name: name.to_string(),
+                        header: raw.to_string(),
+                        lines: Vec::new(),
+                    });
+                    continue;
+                } else {
+                    let (key, value) = line
+                        .split_once('=')
+                        .ok_or_else(|| parse_error(index, "expected `key=value`"))?;
+                    let key = key.trim_end();
+                    if !is_valid_key(key) {
+                        return Err(parse_error(index, &format!("invalid key {:?}", key)));
+                    }
+                    Line::Entry(Entry {
+                        key: key.to_string(),
+                        value: value.trim_start().to_string(),
+                        raw: Some(raw.to_string()),
+                    })
+                };
+
+                match (groups.last_mut(), parsed) {
+                    (Some(group), parsed) => group.lines.push(parsed),
+                    (None, Line::Entry(_)) => {
+                        return Err(parse_error(index, "entry before the first group header"))
+                    }
+                    (None, parsed) => preamble.push(parsed),
+                }
+            }
+        }
+
+        Ok(DesktopEntry {
+            preamble,
+            groups,
+            trailing_newline,
+        })
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.groups.iter()
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    pub fn group_mut(&mut self, name: &str) -> Option<&mut Group> {
+        self.groups.iter_mut().find(|group| group.name == name)
+    }
+
+    /// Return the group named `name`, appending an empty one if it does not
+    /// exist yet.
+    pub fn ensure_group(&mut self, name: &str) -> &mut Group {
+        if let Some(index) = self.groups.iter().position(|group| group.name == name) {
+            return &mut self.groups[index];
+        }
+
+        if let Some(last) = self.groups.last_mut() {
+            if !matches!(last.lines.last(), Some(Line::Blank(_))) {
+                last.lines.push(Line::Blank(String::new()));
+            }
+        }
+        self.groups.push(Group {
+            name: name.to_string(),
+            header: format!("[{}]", name),
+            lines: Vec::new(),
+        });
+        self.groups.last_mut().unwrap()
+    }
+
+    /// Remove the group named `name`. Returns whether it was present.
+    pub fn remove_group(&mut self, name: &str) -> bool {
+        let before = self.groups.len();
+        self.groups.retain(|group| group.name != name);
+        before != self.groups.len()
+    }
+}
+
+impl fmt::Display for DesktopEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        for line in &self.preamble {
+            lines.push(line.to_string());
+        }
+        for group in &self.groups {
+            lines.push(group.header.clone());
+            for line in &group.lines {
+                lines.push(line.to_string());
+            }
+        }
+
+        write!(f, "{}", lines.join("\n"))?;
+        if self.trailing_newline {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Group {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            _ => None,
+        })
+    }
+
+    /// Raw (still escaped) value of `key`. `key` may carry a locale suffix.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.value.as_str())
+    }
+
+    /// Unescaped value of `key`.
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        self.get(key).map(unescape)
+    }
+
+    /// Unescaped value of a `;` separated list such as `Categories`.
+    pub fn get_list(&self, key: &str) -> Option<Vec<String>> {
+        self.get(key).map(split_list)
+    }
+
+    /// Look up a localized value the way the specification describes:
+    /// `lang_COUNTRY@MODIFIER`, `lang_COUNTRY`, `lang@MODIFIER`, `lang`,
+    /// then the unlocalized key.
+    pub fn get_localized(&self, key: &str, locale: &str) -> Option<String> {
+        locale_fallbacks(locale)
+            .iter()
+            .find_map(|locale| self.get_string(&format!("{}[{}]", key, locale)))
+            .or_else(|| self.get_string(key))
+    }
+
+    /// Set the raw value of `key`, replacing it in place if present and
+    /// appending it after the last entry of the group otherwise.
+    pub fn set(&mut self, key: &str, value: &str) {
+        if let Some(entry) = self.entry_mut(key) {
+            if entry.value != value {
+                entry.value = value.to_string();
+                entry.raw = None;
+            }
+            return;
+        }
+
+        let position = self
+            .lines
+            .iter()
+            .rposition(|line| matches!(line, Line::Entry(_)))
+            .map_or(0, |index| index + 1);
+        self.lines.insert(
+            position,
+            Line::Entry(Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+                raw: None,
+            }),
+        );
+    }
+
+    /// Escape `value` and store it under `key`.
+    pub fn set_string(&mut self, key: &str, value: &str) {
+        self.set(key, &escape(value));
+    }
+
+    /// Remove every entry named `key`. Returns whether anything was removed.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let before = self.lines.len();
+        self.lines
+            .retain(|line| !matches!(line, Line::Entry(entry) if entry.key == key));
+        before != self.lines.len()
+    }
+
+    /// Rename `from` to `to`, keeping the entry at its position. An existing
+    /// `to` entry is dropped. Returns whether `from` was present.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        if from == to || self.get(from).is_none() {
+            return self.get(from).is_some();
+        }
+
+        self.remove(to);
+        let entry = self.entry_mut(from).unwrap();
+        entry.key = to.to_string();
+        entry.raw = None;
+        true
+    }
+
+    fn entry_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.lines.iter_mut().find_map(|line| match line {
+            Line::Entry(entry) if entry.key == key => Some(entry),
+            _ => None,
+        })
+    }
+}
+
+impl Entry {
+    /// Full key including the locale suffix.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Key without the locale suffix.
+    pub fn base_key(&self) -> &str {
+        split_key(&self.key).0
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        split_key(&self.key).1
+    }
+
+    /// Raw (still escaped) value.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Blank(raw) | Line::Comment(raw) => write!(f, "{}", raw),
+            Line::Entry(Entry { raw: Some(raw), .. }) => write!(f, "{}", raw),
+            Line::Entry(Entry { key, value, .. }) => write!(f, "{}={}", key, value),
+        }
+    }
+}
+
+/// Split `Name[de_DE]` into `("Name", Some("de_DE"))`.
+pub fn split_key(key: &str) -> (&str, Option<&str>) {
+    match key.strip_suffix(']').and_then(|key| key.split_once('[')) {
+        Some((base, locale)) => (base, Some(locale)),
+        None => (key, None),
+    }
+}
+
+/// Resolve the escape sequences `\s`, `\n`, `\t`, `\r` and `\\`. Unknown
+/// sequences are kept as they are.
+pub fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => result.push(' '),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Inverse of [`unescape`]. Leading spaces are written as `\s` so they
+/// survive the whitespace trimming around `=`. Like `unescape`, this leaves
+/// the `\;` of list values alone, so a changed list keeps its elements.
+pub fn escape(value: &str) -> String {
+    escape_with(value, true)
+}
+
+fn escape_with(value: &str, keep_escaped_semicolons: bool) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut leading = true;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' if leading => result.push_str("\\s"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            '\\' if keep_escaped_semicolons && chars.peek() == Some(&';') => result.push('\\'),
+            '\\' => result.push_str("\\\\"),
+            c => result.push(c),
+        }
+        leading = leading && c == ' ';
+    }
+    result
+}
+
+/// Split a raw `;` separated list value, honouring `\;`, and unescape each
+/// element. A trailing `;` does not produce an empty element.
+pub fn split_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(';') => current.push(';'),
+                Some(other) => {
+                    current.push('\\');
+                    current.push(other);
+                }
+                None => current.push('\\'),
+            },
+            ';' => items.push(unescape(&std::mem::take(&mut current))),
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        items.push(unescape(&current));
+    }
+    items
+}
+
+/// Inverse of [`split_list`], ending every element with `;`.
+pub fn join_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("{};", escape_with(item, false).replace(';', "\\;")))
+        .collect()
+}
+
+fn locale_fallbacks(locale: &str) -> Vec<String> {
+    let locale = locale.split('.').next().unwrap_or(locale);
+    let (rest, modifier) = match locale.split_once('@') {
+        Some((rest, modifier)) => (rest, Some(modifier)),
+        None => (locale, None),
+    };
+    let (lang, country) = match rest.split_once('_') {
+        Some((lang, country)) => (lang, Some(country)),
+        None => (rest, None),
+    };
+
+    let mut result = Vec::new();
+    if let (Some(country), Some(modifier)) = (country, modifier) {
+        result.push(format!("{}_{}@{}", lang, country, modifier));
+    }
+    if let Some(country) = country {
+        result.push(format!("{}_{}", lang, country));
+    }
+    if let Some(modifier) = modifier {
+        result.push(format!("{}@{}", lang, modifier));
+    }
+    result.push(lang.to_string());
+    result
+}
+
+fn parse_group_header(line: &str) -> Option<&str> {
+    let name = line.trim_end().strip_prefix('[')?.strip_suffix(']')?;
+    if name.is_empty() || name.contains(['[', ']']) || name.chars().any(|c| c.is_control()) {
+        return None;
+    }
+    Some(name)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let (base, locale) = split_key(key);
+    !base.is_empty()
+        && base.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && locale.is_none_or(|locale| !locale.is_empty() && !locale.contains(['[', ']', '=']))
+}
+
+fn parse_error(index: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        ParseError {
+            line: index + 1,
+            message: message.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "# Comment before groups\n\
+\n\
+[Desktop Entry]\n\
+Type=Application\n\
+Name=Text Editor\n\
+Name[de]=Texteditor\n\
+Name[sr@latin]=Uređivač teksta\n\
+Comment = Edit\\stext\\nfiles\n\
+Categories=Utility;TextEditor;Semi\\;colon;\n\
+# A comment inside a group\n\
+Exec=editor %U\n\
+\n\
+[Desktop Action new-window]\n\
+Name=New Window\n\
+Exec=editor --new-window\n";
+
+    #[test]
+    fn test_round_trip() {
+        for content in [
+            SAMPLE,
+            "",
+            "\n",
+            "[Desktop Entry]",
+            "[Desktop Entry]\r\nName=CRLF\r\n",
+            "[Desktop Entry]\nName=x\n\n\n",
+        ] {
+            let entry = DesktopEntry::parse(content).unwrap();
+            assert_eq!(entry.to_string(), content);
+        }
+    }
+
+    #[test]
+    fn test_parse_structure() {
+        let entry = DesktopEntry::parse(SAMPLE).unwrap();
+        let names: Vec<_> = entry.groups().map(|group| group.name()).collect();
+        assert_eq!(names, ["Desktop Entry", "Desktop Action new-window"]);
+
+        let main = entry.group("Desktop Entry").unwrap();
+        assert_eq!(main.get("Type"), Some("Application"));
+        assert_eq!(main.get("Name[de]"), Some("Texteditor"));
+        assert_eq!(main.get_string("Comment").unwrap(), "Edit text\nfiles");
+        assert_eq!(
+            main.get_list("Categories").unwrap(),
+            ["Utility", "TextEditor", "Semi;colon"]
+        );
+
+        let localized: Vec<_> = main
+            .entries()
+            .filter(|entry| entry.base_key() == "Name")
+            .map(|entry| entry.locale())
+            .collect();
+        assert_eq!(localized, [None, Some("de"), Some("sr@latin")]);
+    }
+
+    #[test]
+    fn test_get_localized() {
+        let entry = DesktopEntry::parse(SAMPLE).unwrap();
+        let main = entry.group("Desktop Entry").unwrap();
+        assert_eq!(
+            main.get_localized("Name", "de_DE.UTF-8").unwrap(),
+            "Texteditor"
+        );
+        assert_eq!(
+            main.get_localized("Name", "sr_RS@latin").unwrap(),
+            "Uređivač teksta"
+        );
+        assert_eq!(main.get_localized("Name", "fr").unwrap(), "Text Editor");
+    }
+
+    #[test]
+    fn test_modify() {
+        let mut entry = DesktopEntry::parse(SAMPLE).unwrap();
+        let main = entry.group_mut("Desktop Entry").unwrap();
+        main.set("Exec", "editor --wayland %U");
+        main.set("StartupWMClass", "editor");
+        assert!(main.remove("Name[de]"));
+        assert!(main.rename("Comment", "GenericName"));
+        assert!(!main.remove("Missing"));
+
+        let action = entry.ensure_group("X-Extra");
+        action.set_string("Key", " padded");
+
+        assert_eq!(
+            entry.to_string(),
+            "# Comment before groups\n\
+\n\
+[Desktop Entry]\n\
+Type=Application\n\
+Name=Text Editor\n\
+Name[sr@latin]=Uređivač teksta\n\
+GenericName=Edit\\stext\\nfiles\n\
+Categories=Utility;TextEditor;Semi\\;colon;\n\
+# A comment inside a group\n\
+Exec=editor --wayland %U\n\
+StartupWMClass=editor\n\
+\n\
+[Desktop Action new-window]\n\
+Name=New Window\n\
+Exec=editor --new-window\n\
+\n\
+[X-Extra]\n\
+Key=\\spadded\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for content in [
+            "Name=Before group\n[Desktop Entry]\n",
+            "[Desktop Entry]\nno equals sign\n",
+            "[Desktop Entry\n",
+            "[Desktop Entry]\n[Desktop Entry]\n",
+            "[Desktop Entry]\nBad_Key=1\n",
+        ] {
+            let err = DesktopEntry::parse(content).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        for value in [
+            "plain",
+            "  two leading",
+            "tab\there",
+            "back\\slash",
+            "new\nline",
+            "Semi\\;colon;Other;",
+        ] {
+            assert_eq!(unescape(&escape(value)), value);
+        }
+        assert_eq!(escape("Semi\\;colon;C:\\"), "Semi\\;colon;C:\\\\");
+
+        // A changed list value keeps its escaped semicolons.
+        let mut entry = DesktopEntry::parse("[Desktop Entry]\nKeywords=Semi\\;colon;\n").unwrap();
+        let group = entry.group_mut("Desktop Entry").unwrap();
+        let value = group.get_string("Keywords").unwrap();
+        group.set_string("Keywords", &format!("{}Other;", value));
+        assert_eq!(group.get_list("Keywords").unwrap(), ["Semi;colon", "Other"]);
+
+        for items in [vec!["a;b", "back\\slash", "c\\;", " d"], vec![]] {
+            let items: Vec<String> = items.into_iter().map(String::from).collect();
+            assert_eq!(split_list(&join_list(&items)), items);
+        }
+    }
+}
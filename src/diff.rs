@@ -0,0 +1,82 @@
+use similar::TextDiff;
+use std::io::{self, Write};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Write a unified diff between `old` and `new`, coloured with ANSI escape
+/// codes if `color` is set.
+pub fn write_unified_diff(
+    writer: &mut impl Write,
+    old: &str,
+    new: &str,
+    old_name: &str,
+    new_name: &str,
+    color: bool,
+) -> io::Result<()> {
+    let diff = TextDiff::from_lines(old, new);
+    let unified = diff
+        .unified_diff()
+        .context_radius(3)
+        .header(old_name, new_name)
+        .to_string();
+
+    for line in unified.lines() {
+        let style = if !color {
+            ""
+        } else if line.starts_with("---") || line.starts_with("+++") {
+            BOLD
+        } else if line.starts_with("@@") {
+            CYAN
+        } else if line.starts_with('-') {
+            RED
+        } else if line.starts_with('+') {
+            GREEN
+        } else {
+            ""
+        };
+
+        if style.is_empty() {
+            writeln!(writer, "{}", line)?;
+        } else {
+            writeln!(writer, "{}{}{}", style, line, RESET)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_unified_diff() {
+        let mut output = Vec::new();
+        write_unified_diff(
+            &mut output,
+            "[Desktop Entry]\nName=Old\n",
+            "[Desktop Entry]\nName=New\n",
+            "a.desktop",
+            "b.desktop",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "--- a.desktop\n+++ b.desktop\n@@ -1,2 +1,2 @@\n [Desktop Entry]\n-Name=Old\n+Name=New\n"
+        );
+    }
+
+    #[test]
+    fn test_write_unified_diff_color() {
+        let mut output = Vec::new();
+        write_unified_diff(&mut output, "a\n", "b\n", "old", "new", true).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\x1b[31m-a\x1b[0m"));
+        assert!(output.contains("\x1b[32m+b\x1b[0m"));
+    }
+}
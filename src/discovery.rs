@@ -0,0 +1,396 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::xdg;
+
+/// Data directory snapd exports the desktop files of snaps to.
+const SNAP_EXPORT_ROOT: &str = "/var/lib/snapd/desktop";
+/// Data directory of the system wide Flatpak installation.
+const FLATPAK_EXPORT_ROOT: &str = "/var/lib/flatpak/exports/share";
+
+/// A desktop file found in a source directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopFile {
+    /// Desktop file ID. For applications it is the path relative to the
+    /// `applications` directory with `/` replaced by `-`, e.g.
+    /// `kde4-foo.desktop`, for other kinds the file name.
+    pub id: String,
+    pub path: PathBuf,
+    /// Data directory, or config directory for autostart entries, the file
+    /// was found in, e.g. `/usr/share`.
+    pub data_dir: PathBuf,
+    pub kind: Kind,
+    pub origin: Origin,
+}
+
+/// The kind of files a generator applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Desktop entries in `applications` of the data directories.
+    #[default]
+    Application,
+    /// Desktop entries in `autostart` of the config directories.
+    Autostart,
+    /// Menu directory entries in `desktop-directories` of the data
+    /// directories.
+    Directory,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 3] = [Kind::Application, Kind::Autostart, Kind::Directory];
+
+    /// Name of the directory holding files of this kind, in the data or
+    /// config directories.
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            Kind::Application => "applications",
+            Kind::Autostart => "autostart",
+            Kind::Directory => "desktop-directories",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Kind::Application | Kind::Autostart => "desktop",
+            Kind::Directory => "directory",
+        }
+    }
+
+    /// Only applications may be put into subdirectories.
+    fn is_recursive(self) -> bool {
+        self == Kind::Application
+    }
+}
+
+/// Where a desktop file comes from, told by the data directory it is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// Installed with the system, e.g. by the package manager.
+    System,
+    /// Exported by a Flatpak installation.
+    Flatpak,
+    /// Exported by snapd.
+    Snap,
+}
+
+impl Origin {
+    /// The origin of the desktop files in `applications_dir`.
+    pub fn of(applications_dir: &Path) -> Origin {
+        let data_dir = applications_dir.parent().unwrap_or(applications_dir);
+        if data_dir.ends_with("flatpak/exports/share") {
+            Origin::Flatpak
+        } else if data_dir.ends_with("snapd/desktop") {
+            Origin::Snap
+        } else {
+            Origin::System
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Origin::System => "system",
+            Origin::Flatpak => "flatpak",
+            Origin::Snap => "snap",
+        })
+    }
+}
+
+/// Add the Flatpak and Snap export roots missing from `data_dirs`, where
+/// their own profile scripts would put them: Flatpak in front of the others,
+/// the user installation first, and Snap at the end.
+pub fn add_export_roots(data_dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let flatpak_roots = [
+        xdg::get_data_home().join("flatpak/exports/share"),
+        PathBuf::from(FLATPAK_EXPORT_ROOT),
+    ];
+
+    let mut result: Vec<PathBuf> = flatpak_roots
+        .into_iter()
+        .filter(|root| !data_dirs.contains(root))
+        .collect();
+    let snap_root = PathBuf::from(SNAP_EXPORT_ROOT);
+    let has_snap_root = data_dirs.contains(&snap_root);
+    result.extend(data_dirs);
+    if !has_snap_root {
+        result.push(snap_root);
+    }
+    result
+}
+
+/// The file of `kind` used for every desktop file ID, searching the
+/// subdirectories of `source_dirs` as well for applications. As the spec
+/// requires, a file in an earlier directory hides files with the same ID in
+/// the later ones.
+pub fn find_desktop_files(kind: Kind, source_dirs: &[PathBuf]) -> io::Result<Vec<DesktopFile>> {
+    let mut desktop_files = Vec::new();
+    let mut ids = HashSet::new();
+
+    for source_dir in source_dirs {
+        if !source_dir.is_dir() {
+            continue;
+        }
+
+        let mut found = Vec::new();
+        let origin = Origin::of(source_dir);
+        walk(kind, source_dir, source_dir, origin, &mut found)?;
+        found.sort_by(|a, b| a.id.cmp(&b.id));
+
+        for desktop_file in found {
+            if ids.insert(desktop_file.id.clone()) {
+                desktop_files.push(desktop_file);
+            } else {
+                debug!(
+                    "{:?} is hidden by a file with the same ID",
+                    desktop_file.path
+                );
+            }
+        }
+    }
+
+    Ok(desktop_files)
+}
+
+/// The ID of the file of `kind` at `path` in `source_dir`, or `None` if it
+/// is not such a file in that directory.
+pub fn desktop_file_id(kind: Kind, source_dir: &Path, path: &Path) -> Option<String> {
+    if path.extension().and_then(|s| s.to_str()) != Some(kind.extension()) {
+        return None;
+    }
+    let relative = path.strip_prefix(source_dir).ok()?;
+    let components = relative
+        .iter()
+        .map(|component| component.to_str())
+        .collect::<Option<Vec<_>>>()?;
+    if components.len() > 1 && !kind.is_recursive() {
+        return None;
+    }
+    Some(components.join("-"))
+}
+
+/// Every subdirectory of `source_dir` files of `kind` are read from, at
+/// any depth and sorted.
+pub fn find_subdirs(kind: Kind, source_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut subdirs = Vec::new();
+    if !kind.is_recursive() {
+        return Ok(subdirs);
+    }
+    let mut pending = vec![source_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                pending.push(entry.path());
+                subdirs.push(entry.path());
+            }
+        }
+    }
+    subdirs.sort();
+    Ok(subdirs)
+}
+
+fn walk(
+    kind: Kind,
+    source_dir: &Path,
+    dir: &Path,
+    origin: Origin,
+    found: &mut Vec<DesktopFile>,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if kind.is_recursive() {
+                walk(kind, source_dir, &path, origin, found)?;
+            }
+            continue;
+        }
+
+        if path.extension().and_then(|s| s.to_str()) != Some(kind.extension()) {
+            continue;
+        }
+        match desktop_file_id(kind, source_dir, &path) {
+            Some(id) => found.push(DesktopFile {
+                id,
+                path,
+                data_dir: source_dir.parent().unwrap_or(source_dir).to_path_buf(),
+                kind,
+                origin,
+            }),
+            None => debug!("Skip {:?}, whose name is not valid UTF-8", path),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_find_desktop_files() {
+        let dir = tempdir().unwrap();
+        let applications_path = dir.path().join("applications");
+        fs::create_dir_all(applications_path.join("kde4")).unwrap();
+        File::create(applications_path.join("test.desktop")).unwrap();
+        File::create(applications_path.join("kde4/foo.desktop")).unwrap();
+        File::create(applications_path.join("kde4/ignored.txt")).unwrap();
+
+        let lower_path = dir.path().join("lower/applications");
+        fs::create_dir_all(&lower_path).unwrap();
+        File::create(lower_path.join("test.desktop")).unwrap();
+        File::create(lower_path.join("kde4-foo.desktop")).unwrap();
+        File::create(lower_path.join("other.desktop")).unwrap();
+
+        let result = find_desktop_files(
+            Kind::Application,
+            &[applications_path.clone(), lower_path.clone()],
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            [
+                DesktopFile {
+                    id: "kde4-foo.desktop".to_string(),
+                    path: applications_path.join("kde4/foo.desktop"),
+                    data_dir: dir.path().to_path_buf(),
+                    kind: Kind::Application,
+                    origin: Origin::System,
+                },
+                DesktopFile {
+                    id: "test.desktop".to_string(),
+                    path: applications_path.join("test.desktop"),
+                    data_dir: dir.path().to_path_buf(),
+                    kind: Kind::Application,
+                    origin: Origin::System,
+                },
+                DesktopFile {
+                    id: "other.desktop".to_string(),
+                    path: lower_path.join("other.desktop"),
+                    data_dir: dir.path().join("lower"),
+                    kind: Kind::Application,
+                    origin: Origin::System,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_subdirs() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("kde4/games")).unwrap();
+        fs::create_dir_all(dir.path().join("wine")).unwrap();
+        File::create(dir.path().join("test.desktop")).unwrap();
+
+        assert_eq!(
+            find_subdirs(Kind::Application, dir.path()).unwrap(),
+            [
+                dir.path().join("kde4"),
+                dir.path().join("kde4/games"),
+                dir.path().join("wine")
+            ]
+        );
+        assert!(find_subdirs(Kind::Directory, dir.path())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_find_directory_files() {
+        let dir = tempdir().unwrap();
+        let directories_path = dir.path().join("desktop-directories");
+        fs::create_dir_all(directories_path.join("sub")).unwrap();
+        File::create(directories_path.join("games.directory")).unwrap();
+        File::create(directories_path.join("sub/ignored.directory")).unwrap();
+        File::create(directories_path.join("ignored.desktop")).unwrap();
+
+        let result =
+            find_desktop_files(Kind::Directory, std::slice::from_ref(&directories_path)).unwrap();
+        assert_eq!(
+            result,
+            [DesktopFile {
+                id: "games.directory".to_string(),
+                path: directories_path.join("games.directory"),
+                data_dir: dir.path().to_path_buf(),
+                kind: Kind::Directory,
+                origin: Origin::System,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_origin() {
+        for (dir, origin) in [
+            ("/usr/share/applications", Origin::System),
+            (
+                "/var/lib/flatpak/exports/share/applications",
+                Origin::Flatpak,
+            ),
+            (
+                "/home/user/.local/share/flatpak/exports/share/applications",
+                Origin::Flatpak,
+            ),
+            ("/var/lib/snapd/desktop/applications", Origin::Snap),
+        ] {
+            assert_eq!(Origin::of(Path::new(dir)), origin);
+        }
+    }
+
+    #[test]
+    fn test_add_export_roots() {
+        let data_dirs = add_export_roots(vec![
+            PathBuf::from("/var/lib/flatpak/exports/share"),
+            PathBuf::from("/usr/share"),
+        ]);
+        assert_eq!(data_dirs.len(), 4);
+        assert!(data_dirs[0].ends_with("flatpak/exports/share"));
+        assert_eq!(
+            data_dirs[1..],
+            [
+                PathBuf::from("/var/lib/flatpak/exports/share"),
+                PathBuf::from("/usr/share"),
+                PathBuf::from("/var/lib/snapd/desktop"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_desktop_file_id() {
+        let dir = Path::new("/usr/share/applications");
+        assert_eq!(
+            desktop_file_id(Kind::Application, dir, &dir.join("kde4/foo.desktop")).as_deref(),
+            Some("kde4-foo.desktop")
+        );
+        assert_eq!(
+            desktop_file_id(Kind::Application, dir, &dir.join("foo.txt")),
+            None
+        );
+        assert_eq!(
+            desktop_file_id(
+                Kind::Application,
+                dir,
+                Path::new("/opt/applications/foo.desktop")
+            ),
+            None
+        );
+
+        let dir = Path::new("/etc/xdg/autostart");
+        assert_eq!(
+            desktop_file_id(Kind::Autostart, dir, &dir.join("foo.desktop")).as_deref(),
+            Some("foo.desktop")
+        );
+        assert_eq!(
+            desktop_file_id(Kind::Autostart, dir, &dir.join("sub/foo.desktop")),
+            None
+        );
+    }
+}
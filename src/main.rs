@@ -1,28 +1,188 @@
 use clap::Command;
-use log::{debug, info};
-use regex::Regex;
-use serde::Deserialize;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashSet};
 use std::env;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::process::{Command as ProcessCommand, Stdio};
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{self, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use config::{CompiledConfig, Config, Generator, OnError, Protocol, Validation};
+use desktop_entry::{DesktopEntry, MAIN_GROUP};
+use discovery::{DesktopFile, Kind};
+use manifest::{FileState, Manifest, ManifestEntry};
+
+mod config;
+mod desktop_entry;
+mod diff;
+mod discovery;
+mod manifest;
+mod migrate;
+mod pool;
+mod protocol;
+mod script;
+mod spec;
+mod subprocess;
+mod systemd;
+mod validate;
+mod watch;
 mod xdg;
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    version: String,
-    generators: Vec<Generator>,
-}
+const CONFIG_FILE_PATH: &str = "xdg-desktop-file-override/config.yaml";
+const CONFIG_DROP_IN_DIR_PATH: &str = "xdg-desktop-file-override/config.d";
+const MANIFEST_FILE_PATH: &str = "xdg-desktop-file-override/manifest.yaml";
+/// Name of the manifest kept inside an output directory given with
+/// `--output-dir`, so that it is tracked separately from the default one.
+const OUTPUT_DIR_MANIFEST_FILE_NAME: &str = ".xdg-desktop-file-override-manifest.yaml";
 
-#[derive(Debug, Deserialize)]
-struct Generator {
-    filter: String,
-    name: String,
-    command: Vec<String>,
+/// Where config and desktop files are read from and generated files are
+/// written to. Anything not given on the command line follows the XDG base
+/// directories.
+#[derive(Debug, Default)]
+struct Paths {
+    config: Option<PathBuf>,
+    data_dirs: Option<Vec<PathBuf>>,
+    output_dir: Option<PathBuf>,
 }
 
-const CONFIG_FILE_PATH: &str = "xdg-desktop-file-override/config.yaml";
+impl Paths {
+    fn from_matches(matches: &clap::ArgMatches) -> io::Result<Paths> {
+        let config = matches
+            .get_one::<PathBuf>("config")
+            .map(std::path::absolute)
+            .transpose()?;
+        let data_dirs = matches
+            .get_one::<String>("data-dirs")
+            .map(|dirs| {
+                env::split_paths(dirs)
+                    .map(std::path::absolute)
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let output_dir = matches
+            .get_one::<PathBuf>("output-dir")
+            .map(std::path::absolute)
+            .transpose()?;
+        Ok(Paths {
+            config,
+            data_dirs,
+            output_dir,
+        })
+    }
+
+    /// The options to pass to another invocation to use the same paths.
+    fn options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Some(config) = &self.config {
+            options.push("--config".to_string());
+            options.push(config.to_string_lossy().to_string());
+        }
+        if let Some(data_dirs) = &self.data_dirs {
+            options.push("--data-dirs".to_string());
+            let joined = env::join_paths(data_dirs).unwrap_or_default();
+            options.push(joined.to_string_lossy().to_string());
+        }
+        if let Some(output_dir) = &self.output_dir {
+            options.push("--output-dir".to_string());
+            options.push(output_dir.to_string_lossy().to_string());
+        }
+        options
+    }
+
+    /// Every config file, from the lowest precedence to the highest.
+    fn config_files(&self) -> io::Result<Vec<PathBuf>> {
+        let Some(config) = &self.config else {
+            return xdg::get_config_files(CONFIG_FILE_PATH, CONFIG_DROP_IN_DIR_PATH);
+        };
+        if !config.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Configuration file {:?} not found", config),
+            ));
+        }
+        Ok(vec![config.clone()])
+    }
+
+    /// Config files and drop-in directories which may appear or change.
+    fn config_locations(&self) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        if let Some(config) = &self.config {
+            return (vec![config.clone()], Vec::new());
+        }
+        let config_dirs = xdg::get_config_dirs();
+        let config_paths = config_dirs
+            .iter()
+            .map(|dir| dir.join(CONFIG_FILE_PATH))
+            .collect();
+        let drop_in_dirs = config_dirs
+            .iter()
+            .map(|dir| dir.join(CONFIG_DROP_IN_DIR_PATH))
+            .collect();
+        (config_paths, drop_in_dirs)
+    }
+
+    /// Directories to read files of `kind` from, in order of preference.
+    fn source_dirs(&self, kind: Kind) -> Vec<PathBuf> {
+        let base_dirs = match kind {
+            // `$XDG_CONFIG_HOME/autostart` is where overrides are written.
+            Kind::Autostart => xdg::get_config_dirs().split_off(1),
+            Kind::Application | Kind::Directory => match &self.data_dirs {
+                Some(data_dirs) => data_dirs.clone(),
+                None => {
+                    let xdg_data_dirs = env::var("XDG_DATA_DIRS")
+                        .unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
+                    discovery::add_export_roots(env::split_paths(&xdg_data_dirs).collect())
+                }
+            },
+        };
+        base_dirs
+            .iter()
+            .map(|path| path.join(kind.dir_name()))
+            .collect()
+    }
+
+    /// Source directories of every kind.
+    fn all_source_dirs(&self) -> Vec<PathBuf> {
+        Kind::ALL
+            .into_iter()
+            .flat_map(|kind| self.source_dirs(kind))
+            .collect()
+    }
+
+    /// Directory overrides of `kind` are written to.
+    fn output_dir(&self, kind: Kind) -> PathBuf {
+        let base_dir = match (&self.output_dir, kind) {
+            (Some(output_dir), _) => output_dir.clone(),
+            (None, Kind::Autostart) => xdg::get_config_home(),
+            (None, Kind::Application | Kind::Directory) => xdg::get_data_home(),
+        };
+        base_dir.join(kind.dir_name())
+    }
+
+    /// Where the override of the file `id` of `kind` is written.
+    fn output_path(&self, kind: Kind, id: &str) -> PathBuf {
+        self.output_dir(kind).join(id)
+    }
+
+    /// Where the override of the file at `path` in one of the source
+    /// directories is written.
+    fn output_path_of(&self, path: &Path) -> Option<PathBuf> {
+        Kind::ALL.into_iter().find_map(|kind| {
+            self.source_dirs(kind)
+                .iter()
+                .find_map(|dir| discovery::desktop_file_id(kind, dir, path))
+                .map(|id| self.output_path(kind, &id))
+        })
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        match &self.output_dir {
+            Some(output_dir) => output_dir.join(OUTPUT_DIR_MANIFEST_FILE_NAME),
+            None => xdg::get_state_home().join(MANIFEST_FILE_PATH),
+        }
+    }
+}
 
 fn main() -> io::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
@@ -40,164 +200,1146 @@ fn main() -> io::Result<()> {
                 .num_args(0)
                 .required(false),
         )
+        .arg(
+            clap::Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .help("Use only this config file instead of the XDG config directories")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            clap::Arg::new("data-dirs")
+                .long("data-dirs")
+                .value_name("LIST")
+                .help("Colon separated data directories to read desktop files from, instead of $XDG_DATA_DIRS")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::new("output-dir")
+                .long("output-dir")
+                .value_name("DIR")
+                .help("Directory holding the applications, autostart and desktop-directories directories to write to, instead of $XDG_DATA_HOME and $XDG_CONFIG_HOME")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Validate the configuration files.")
+                .arg(
+                    clap::Arg::new("show-config")
+                        .long("show-config")
+                        .help("Print the merged configuration with the origin of every generator")
+                        .num_args(0)
+                        .required(false),
+                ),
+        )
         .subcommand(Command::new("clean").about("Remove all generated desktop files."))
-        .subcommand(Command::new("generate").about("Generate override desktop files."));
+        .subcommand(
+            Command::new("migrate-config")
+                .about(
+                    "Rewrite the configuration files writable by this user into the current schema.",
+                )
+                .arg(
+                    clap::Arg::new("print")
+                        .long("print")
+                        .help("Print the migrated configuration instead of writing it")
+                        .num_args(0)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("generate")
+                .about("Generate override desktop files.")
+                .arg(
+                    clap::Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Print a diff of every change instead of writing files")
+                        .num_args(0)
+                        .required(false),
+                )
+                .arg(jobs_arg()),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Regenerate override desktop files whenever desktop files or config change.")
+                .arg(
+                    clap::Arg::new("debounce")
+                        .long("debounce")
+                        .help("Milliseconds without events to wait before regenerating")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("500"),
+                )
+                .arg(jobs_arg()),
+        )
+        .subcommand(
+            Command::new("install-units")
+                .about("Install systemd user units regenerating override desktop files on changes.")
+                .arg(
+                    clap::Arg::new("print")
+                        .long("print")
+                        .help("Print the units instead of writing them")
+                        .num_args(0)
+                        .required(false),
+                ),
+        )
+        .subcommand(Command::new("uninstall-units").about("Remove the systemd user units."));
 
     debug!("version: {}", command.get_version().unwrap());
 
     let matches = command.get_matches();
 
+    let paths = Paths::from_matches(&matches)?;
+    let manifest_path = paths.manifest_path();
+
+    if let Some(matches) = matches.subcommand_matches("check") {
+        return check_config(&paths, matches.get_flag("show-config"));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("migrate-config") {
+        return migrate_config(&paths, matches.get_flag("print"));
+    }
+
     if let Some(_matches) = matches.subcommand_matches("clean") {
-        clean_generated_files()?;
+        let mut manifest = load_manifest(&paths)?;
+        clean_generated_files(&mut manifest)?;
+        manifest.save(&manifest_path)?;
         return Ok(());
     }
 
-    if let Some(_matches) = matches.subcommand_matches("generate") {
-        clean_generated_files()?;
-        generate_files()?;
+    if let Some(matches) = matches.subcommand_matches("generate") {
+        let jobs = get_jobs(matches);
+        if matches.get_flag("dry-run") {
+            // Legacy files are only cleaned up when actually generating.
+            let mut manifest = Manifest::load(&manifest_path)?.unwrap_or_default();
+            return generate_files(&paths, true, jobs, &mut manifest, None);
+        }
+
+        let mut manifest = load_manifest(&paths)?;
+        let result = generate_files(&paths, false, jobs, &mut manifest, None);
+        manifest.save(&manifest_path)?;
+        return result;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("watch") {
+        let debounce = Duration::from_millis(*matches.get_one::<u64>("debounce").unwrap());
+        return watch(&paths, debounce, get_jobs(matches));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("install-units") {
+        return install_units(&paths, matches.get_flag("print"));
+    }
+
+    if let Some(_matches) = matches.subcommand_matches("uninstall-units") {
+        for path in systemd::uninstall_units(&get_unit_dir())? {
+            info!("Removed {:?}", path);
+        }
         return Ok(());
     }
 
-    return Err(io::Error::new(
+    Err(io::Error::new(
         io::ErrorKind::InvalidInput,
         "Invalid subcommand",
-    ));
+    ))
 }
 
-fn generate_files() -> io::Result<()> {
-    let (path, config_file) = xdg::get_config_file(CONFIG_FILE_PATH)?;
-    debug!("Use config file {:?}", path);
+/// `--jobs` of `generate` and `watch`.
+fn jobs_arg() -> clap::Arg {
+    clap::Arg::new("jobs")
+        .short('j')
+        .long("jobs")
+        .value_name("N")
+        .help("Number of desktop files to generate in parallel, the number of CPUs by default")
+        .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+}
 
-    let config: Config = serde_yaml::from_reader(config_file)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+/// The `--jobs` given, or the number of CPUs.
+fn get_jobs(matches: &clap::ArgMatches) -> usize {
+    matches
+        .get_one::<usize>("jobs")
+        .copied()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+fn get_unit_dir() -> PathBuf {
+    xdg::get_config_home().join("systemd/user")
+}
+
+fn install_units(paths: &Paths, print: bool) -> io::Result<()> {
+    // `PathChanged=` does not look into subdirectories, so the ones existing
+    // now are watched on their own.
+    let mut watched = Vec::new();
+    for kind in Kind::ALL {
+        for dir in paths.source_dirs(kind) {
+            let subdirs = if dir.is_dir() {
+                discovery::find_subdirs(kind, &dir)?
+            } else {
+                Vec::new()
+            };
+            watched.push(dir);
+            watched.extend(subdirs);
+        }
+    }
+    let (config_paths, drop_in_dirs) = paths.config_locations();
+    watched.extend(config_paths);
+    watched.extend(drop_in_dirs);
+    let path_unit = systemd::render_path_unit(&watched);
+    let service_unit = systemd::render_service_unit(&env::current_exe()?, &paths.options());
+
+    if print {
+        println!("# {}\n{}", systemd::PATH_UNIT_NAME, path_unit);
+        println!("# {}\n{}", systemd::SERVICE_UNIT_NAME, service_unit);
+        return Ok(());
+    }
+
+    for path in systemd::install_units(&get_unit_dir(), &path_unit, &service_unit)? {
+        info!("Wrote {:?}", path);
+    }
+    info!(
+        "Run `systemctl --user daemon-reload && systemctl --user enable --now {}` to activate it",
+        systemd::PATH_UNIT_NAME
+    );
+    Ok(())
+}
+
+/// Load, validate and merge every config file, logging every problem found.
+fn load_config(paths: &Paths) -> io::Result<CompiledConfig> {
+    let mut configs = Vec::new();
+    let mut invalid = Vec::new();
+    for (path, result) in read_configs(paths)? {
+        match result {
+            Ok(config) => configs.push(config),
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    error!("{}", diagnostic);
+                }
+                invalid.push(path);
+            }
+        }
+    }
+
+    if !invalid.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid config files {:?}", invalid),
+        ));
+    }
+
+    let config = Config::merge(configs);
     debug!("Config version: {}", config.version);
+    CompiledConfig::new(config)
+}
 
-    let desktop_files = get_desktop_files()?;
+/// Read and validate every config file, from the lowest precedence to the
+/// highest.
+fn read_configs(paths: &Paths) -> io::Result<Vec<(PathBuf, validate::LoadResult)>> {
+    let mut configs = Vec::new();
+    for path in paths.config_files()? {
+        debug!("Use config file {:?}", path);
 
-    // Process each desktop file
-    for desktop_file in desktop_files {
-        let content = std::fs::read_to_string(&desktop_file)?;
-        let mut new_content = content.clone();
-        let mut updated = false;
+        let content = std::fs::read_to_string(&path)?;
+        let result = validate::load(&path, &content);
+        configs.push((path, result));
+    }
+    Ok(configs)
+}
 
-        for generator in &config.generators {
-            let re = Regex::new(&generator.filter).unwrap();
-            if !re.is_match(desktop_file.file_name().unwrap().to_str().unwrap()) {
-                continue;
+fn check_config(paths: &Paths, show_config: bool) -> io::Result<()> {
+    let mut configs = Vec::new();
+    let mut problems = 0;
+    for (path, result) in read_configs(paths)? {
+        match result {
+            Ok(config) => {
+                println!("{}: OK", path.display());
+                configs.push(config);
             }
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    println!("{}", diagnostic);
+                }
+                problems += diagnostics.len();
+            }
+        }
+    }
 
-            debug!(
-                "Applying generator {} on {}",
-                generator.name,
-                desktop_file.display(),
+    if problems == 0 {
+        if show_config {
+            print!("{}", Config::merge(configs).to_annotated_yaml());
+        }
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} problems found", problems),
+    ))
+}
+
+fn migrate_config(paths: &Paths, print: bool) -> io::Result<()> {
+    for path in paths.config_files()? {
+        let content = std::fs::read_to_string(&path)?;
+
+        let (migrated, kept_comments) = migrate::migrate_content(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        if !kept_comments {
+            warn!("Comments and formatting of {:?} could not be kept", path);
+        }
+
+        if print {
+            println!("# {}", path.display());
+            print!("{}", migrated);
+            continue;
+        }
+
+        if migrated == content {
+            info!("{:?} is up to date", path);
+            continue;
+        }
+        if !is_writable(&path) {
+            warn!(
+                "{:?} is outdated but not writable by this user, it is left as is",
+                path
             );
+            continue;
+        }
 
-            let output = apply_generator(&generator.command, &new_content)?;
-            if !output.status.success() {
+        let mut backup = path.clone().into_os_string();
+        backup.push(".bak");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Cannot back up {:?} to {:?}: {}", path, backup, e),
+                )
+            })?;
+        std::fs::write(&path, migrated)?;
+        info!(
+            "Migrated {:?} to version {}, the old one is kept at {:?}",
+            path,
+            migrate::CONFIG_VERSION,
+            backup
+        );
+    }
+    Ok(())
+}
+
+/// Whether this user may rewrite the file at `path` and create a backup next
+/// to it.
+fn is_writable(path: &Path) -> bool {
+    let access = |path: &Path| {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        // SAFETY: `path` is a NUL terminated string living across the call.
+        unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+    };
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    access(path) && access(dir)
+}
+
+/// Load the manifest of generated files. If there is none yet, files
+/// written by versions without a manifest are removed by looking for the
+/// override marker instead.
+fn load_manifest(paths: &Paths) -> io::Result<Manifest> {
+    let path = paths.manifest_path();
+    if let Some(manifest) = Manifest::load(&path)? {
+        debug!("Use manifest {:?}", path);
+        return Ok(manifest);
+    }
+
+    debug!(
+        "Manifest {:?} not found, clean legacy generated files",
+        path
+    );
+    clean_legacy_files(&paths.output_dir(Kind::Application))?;
+    Ok(Manifest::default())
+}
+
+/// Regenerate on every change until an error occurs while watching. Errors
+/// during generation are logged, so a broken config or generator does not
+/// stop the service.
+fn watch(paths: &Paths, debounce: Duration, jobs: usize) -> io::Result<()> {
+    let (config_paths, drop_in_dirs) = paths.config_locations();
+    let mut watcher = watch::Watcher::new(&paths.all_source_dirs(), &config_paths, &drop_in_dirs)?;
+    let manifest_path = paths.manifest_path();
+    let mut manifest = load_manifest(paths)?;
+
+    info!("Watching for changes");
+    let mut affected = None;
+    loop {
+        if let Err(e) = generate_files(paths, false, jobs, &mut manifest, affected.as_ref()) {
+            error!("Failed to generate desktop files: {}", e);
+        }
+        if let Err(e) = manifest.save(&manifest_path) {
+            error!("Failed to save manifest {:?}: {}", manifest_path, e);
+        }
+
+        let changes = watcher.wait(debounce)?;
+        affected = if changes.rescan {
+            info!("Regenerating all desktop files");
+            None
+        } else {
+            info!("Regenerating {} desktop files", changes.desktop_files.len());
+            Some(
+                changes
+                    .desktop_files
+                    .iter()
+                    .filter_map(|path| paths.output_path_of(path))
+                    .collect(),
+            )
+        };
+    }
+}
+
+/// A file to run the generators on.
+struct Job<'a> {
+    file: DesktopFile,
+    output_path: PathBuf,
+    content: String,
+    generators: Vec<&'a Generator>,
+    fingerprint: String,
+}
+
+/// Generate override desktop files, running the generators of up to `jobs`
+/// files in parallel. If `affected` is set, only sources whose output path
+/// is in it are processed and only those outputs are considered stale, so
+/// unrelated files are not even read. Fails after the run if any generator
+/// failed, once every failure has been reported.
+fn generate_files(
+    paths: &Paths,
+    dry_run: bool,
+    jobs: usize,
+    manifest: &mut Manifest,
+    affected: Option<&HashSet<PathBuf>>,
+) -> io::Result<()> {
+    let compiled = load_config(paths)?;
+    let config = &compiled.config;
+    let desktop_files = find_sources(paths, config, manifest)?;
+
+    // Outputs generated or found up to date in this run, any other file in
+    // the manifest is stale, e.g. because its source disappeared.
+    let mut outputs = HashSet::new();
+    let mut unchanged = BTreeMap::new();
+
+    // Find the files to generate, skipping those that are up to date.
+    let mut pending = Vec::new();
+    for file in desktop_files {
+        let output_path = paths.output_path(file.kind, &file.id);
+        if affected.is_some_and(|a| !a.contains(&output_path)) {
+            continue;
+        }
+
+        let content = std::fs::read_to_string(&file.path)?;
+        let generators = matching_generators(&compiled, &file, &content);
+        let desktop_file = file.path.clone();
+        if generators.is_empty() {
+            continue;
+        }
+        let fingerprint = fingerprint(&content, &generators);
+
+        if !dry_run {
+            if let Some(entry) = manifest.files.get(&output_path) {
+                if entry.source == desktop_file
+                    && entry.fingerprint == fingerprint
+                    && entry.state(&output_path)? == FileState::Unchanged
+                {
+                    debug!("{:?} is up to date", output_path);
+                    outputs.insert(output_path);
+                    continue;
+                }
+            }
+
+            if manifest.unchanged.get(&desktop_file) == Some(&fingerprint) {
+                debug!("{:?} is left unchanged by generators", desktop_file);
+                unchanged.insert(desktop_file, fingerprint);
                 continue;
             }
+        }
+
+        pending.push(Job {
+            file,
+            output_path,
+            content,
+            generators,
+            fingerprint,
+        });
+    }
+
+    let mut failures = Vec::new();
+    let mut aborted = false;
+    pool::for_each_ordered(
+        &pending,
+        jobs,
+        |job| generate_file(&compiled, &job.generators, &job.file, &job.content),
+        |index, generated| {
+            let job = &pending[index];
+            for (level, message) in generated.messages {
+                log::log!(level, "{}", message);
+            }
+            // Generate a file some generator failed on again next time.
+            let fingerprint = if generated.failures.is_empty() {
+                job.fingerprint.clone()
+            } else {
+                String::new()
+            };
+            failures.extend(generated.failures);
+
+            if let Some(on_error) = generated.aborted {
+                if dry_run {
+                    print_dry_run(
+                        &job.file.path,
+                        &job.output_path,
+                        &job.generators,
+                        Err(on_error),
+                    )?;
+                } else if manifest.files.contains_key(&job.output_path) {
+                    // Keep the file generated by an earlier run.
+                    outputs.insert(job.output_path.clone());
+                }
+                if on_error == OnError::AbortAll {
+                    aborted = true;
+                    return Ok(ControlFlow::Break(()));
+                }
+                return Ok(ControlFlow::Continue(()));
+            }
+
+            if dry_run {
+                print_dry_run(
+                    &job.file.path,
+                    &job.output_path,
+                    &job.generators,
+                    Ok(generated.content.as_deref()),
+                )?;
+                return Ok(ControlFlow::Continue(()));
+            }
+
+            let Some(new_content) = generated.content else {
+                if !fingerprint.is_empty() {
+                    unchanged.insert(job.file.path.clone(), fingerprint);
+                }
+                return Ok(ControlFlow::Continue(()));
+            };
 
-            let generated_content = String::from_utf8_lossy(&output.stdout).to_string();
-            if generated_content != new_content {
-                new_content = generated_content;
-                updated = true;
+            if let Some(entry) = manifest.files.remove(&job.output_path) {
+                remove_generated_file(&job.output_path, &entry)?;
             }
+
+            let Some(output) = write_new_desktop_file(&job.output_path, &new_content)? else {
+                return Ok(ControlFlow::Continue(()));
+            };
+            manifest.files.insert(
+                job.output_path.clone(),
+                ManifestEntry {
+                    source: job.file.path.clone(),
+                    source_hash: manifest::hash(job.content.as_bytes()),
+                    generators: job.generators.iter().map(|g| g.name.clone()).collect(),
+                    output_hash: manifest::hash(output.as_bytes()),
+                    fingerprint,
+                },
+            );
+            outputs.insert(job.output_path.clone());
+            Ok(ControlFlow::Continue(()))
+        },
+    )?;
+
+    // Files after an aborted run are not known to be stale.
+    if !dry_run && !aborted {
+        let is_affected =
+            |output_path: &PathBuf| affected.is_none_or(|affected| affected.contains(output_path));
+
+        manifest.unchanged.retain(|source, _| {
+            paths
+                .output_path_of(source)
+                .is_some_and(|output_path| !is_affected(&output_path))
+        });
+
+        let stale: Vec<PathBuf> = manifest
+            .files
+            .keys()
+            .filter(|path| is_affected(path) && !outputs.contains(*path))
+            .cloned()
+            .collect();
+        for path in stale {
+            let entry = manifest.files.remove(&path).unwrap();
+            remove_generated_file(&path, &entry)?;
         }
+    }
+    if !dry_run {
+        manifest.unchanged.extend(unchanged);
+    }
 
-        if !updated {
+    report_failures(&failures)
+}
+
+/// Log every failure of this run, failing if there are any.
+fn report_failures(failures: &[Failure]) -> io::Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    error!("{} generator failures:", failures.len());
+    for failure in failures {
+        error!(
+            "  {} on {}: {}",
+            failure.generator,
+            failure.file.display(),
+            failure.error
+        );
+        for line in failure.stderr.lines() {
+            error!("    {}", line);
+        }
+    }
+    Err(io::Error::other(format!(
+        "{} generators failed",
+        failures.len()
+    )))
+}
+
+/// The files of every kind some generator applies to. A file of the same ID
+/// in the output directory, which is not generated by this program, belongs
+/// to the user and hides the file from every source directory.
+fn find_sources(
+    paths: &Paths,
+    config: &Config,
+    manifest: &Manifest,
+) -> io::Result<Vec<DesktopFile>> {
+    let mut desktop_files = Vec::new();
+    for kind in Kind::ALL {
+        if !config.generators.iter().any(|g| g.kind == kind) {
             continue;
         }
 
-        // Write new content to XDG_DATA_HOME/applications
-        write_new_desktop_file(&desktop_file, &new_content)?;
+        // Generated files must not be read back as sources.
+        let output_dir = paths.output_dir(kind);
+        let source_dirs: Vec<PathBuf> = paths
+            .source_dirs(kind)
+            .into_iter()
+            .filter(|dir| *dir != output_dir)
+            .collect();
+
+        let user_ids: HashSet<String> = discovery::find_desktop_files(kind, &[output_dir])?
+            .into_iter()
+            .filter(|file| !manifest.files.contains_key(&file.path))
+            .map(|file| file.id)
+            .collect();
+
+        for file in discovery::find_desktop_files(kind, &source_dirs)? {
+            if user_ids.contains(&file.id) {
+                debug!("{:?} is shadowed by {} of the user", file.path, file.id);
+                continue;
+            }
+            desktop_files.push(file);
+        }
     }
+    Ok(desktop_files)
+}
 
-    Ok(())
+fn matching_generators<'a>(
+    compiled: &'a CompiledConfig,
+    desktop_file: &DesktopFile,
+    content: &str,
+) -> Vec<&'a Generator> {
+    let candidates: Vec<&Generator> = compiled
+        .matching_filters(desktop_file.kind, &desktop_file.id)
+        .collect();
+
+    // Only parse the file if some generator looks at its content.
+    let entry = if candidates.iter().any(|g| g.condition.is_some()) {
+        DesktopEntry::parse(content)
+            .map_err(|e| warn!("Failed to parse {:?}: {}", desktop_file.path, e))
+            .ok()
+    } else {
+        None
+    };
+
+    candidates
+        .into_iter()
+        .filter(|generator| compiled.condition_holds(generator, desktop_file, entry.as_ref()))
+        .collect()
 }
 
-fn get_desktop_files() -> io::Result<Vec<PathBuf>> {
-    let xdg_data_dirs =
-        env::var("XDG_DATA_DIRS").unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
-    let paths: Vec<PathBuf> = env::split_paths(&xdg_data_dirs).collect();
-    let mut desktop_files = Vec::new();
+/// Hash of everything that determines the generated content of one file:
+/// the source content, the definitions of the matching generators and the
+/// version of this program.
+fn fingerprint(content: &str, generators: &[&Generator]) -> String {
+    let mut input = format!("{}\n{}", env!("CARGO_PKG_VERSION"), content);
+    for generator in generators {
+        input.push_str(&serde_yaml::to_string(generator).unwrap());
+    }
+    manifest::hash(input.as_bytes())
+}
 
-    for path in paths {
-        let applications_path = path.join("applications");
-        if !applications_path.exists() {
-            continue;
+/// A generator failing on a file.
+#[derive(Debug)]
+struct Failure {
+    generator: String,
+    file: PathBuf,
+    error: String,
+    stderr: String,
+}
+
+/// The outcome of running the generators on one file.
+#[derive(Debug, Default)]
+struct Generated {
+    /// The new content, or `None` if the file is left unchanged.
+    content: Option<String>,
+    failures: Vec<Failure>,
+    /// Set if a failure aborted the file or the run.
+    aborted: Option<OnError>,
+    /// Log messages, emitted once the file is done so that the log does not
+    /// depend on the order in which files running in parallel finish.
+    messages: Vec<(log::Level, String)>,
+}
+
+impl Generated {
+    fn log(&mut self, level: log::Level, message: String) {
+        self.messages.push((level, message));
+    }
+
+    /// Record `failure`, returning whether to stop running generators on
+    /// this file.
+    fn fail(&mut self, failure: Failure, on_error: OnError) -> bool {
+        let action = match on_error {
+            OnError::Skip => "skip it",
+            OnError::AbortFile => "leave the file as it is",
+            OnError::AbortAll => "stop",
+        };
+        self.log(
+            log::Level::Warn,
+            format!(
+                "Generator {} failed on {}: {}, {}",
+                failure.generator,
+                failure.file.display(),
+                failure.error,
+                action
+            ),
+        );
+        self.failures.push(failure);
+        if on_error == OnError::Skip {
+            return false;
         }
+        self.aborted = Some(on_error);
+        true
+    }
+}
+
+/// Run `generators` on `content` in order, collecting the new content and
+/// the failures, which are handled according to the `on-error` of the
+/// failing generator.
+fn generate_file(
+    compiled: &CompiledConfig,
+    generators: &[&Generator],
+    file: &DesktopFile,
+    content: &str,
+) -> Generated {
+    let config = &compiled.config;
+    let mut generated = Generated::default();
+    let mut new_content = content.to_string();
+    let mut updated = false;
+
+    for generator in generators {
+        generated.log(
+            log::Level::Debug,
+            format!(
+                "Applying generator {} on {}",
+                generator.name,
+                file.path.display(),
+            ),
+        );
 
-        for entry in std::fs::read_dir(applications_path)? {
-            let entry = entry?;
-            if entry.path().extension().and_then(|s| s.to_str()) == Some("desktop") {
-                desktop_files.push(entry.path());
+        let result = run_generator(compiled, generator, file, &new_content, &mut generated)
+            .and_then(|output| {
+                check_output(
+                    config,
+                    generator,
+                    file,
+                    &new_content,
+                    output,
+                    &mut generated,
+                )
+            });
+        match result {
+            Ok(generated_content) => {
+                if generated_content != new_content {
+                    new_content = generated_content;
+                    updated = true;
+                }
+            }
+            Err(failure) => {
+                if generated.fail(failure, generator.on_error(config)) {
+                    return generated;
+                }
             }
         }
     }
 
-    Ok(desktop_files)
+    generated.content = updated.then_some(new_content);
+    generated
+}
+
+/// Apply the actions, the script and the command of `generator` to
+/// `content` in that order, returning the output of the last of them.
+fn run_generator(
+    compiled: &CompiledConfig,
+    generator: &Generator,
+    file: &DesktopFile,
+    content: &str,
+    generated: &mut Generated,
+) -> Result<String, Failure> {
+    let failure = |error: String, stderr: String| Failure {
+        generator: generator.name.clone(),
+        file: file.path.clone(),
+        error,
+        stderr,
+    };
+    let mut new_content = content.to_string();
+
+    if generator.has_actions() {
+        new_content = apply_actions(generator, &new_content)
+            .map_err(|e| failure(format!("actions failed: {}", e), String::new()))?;
+    }
+
+    if let Some(script) = compiled.script(generator) {
+        let output = apply_script(script, &new_content, &generator.limits())
+            .map_err(|e| failure(format!("script failed: {}", e), String::new()))?;
+        for line in output.log {
+            generated.log(
+                log::Level::Debug,
+                format!(
+                    "Generator {} on {}: {}",
+                    generator.name,
+                    file.path.display(),
+                    line
+                ),
+            );
+        }
+        new_content = output.entry.to_string();
+    }
+
+    if generator.command.is_empty() {
+        return Ok(new_content);
+    }
+
+    let output = apply_generator(generator, file, &new_content).map_err(|e| {
+        let stderr = String::from_utf8_lossy(&e.stderr).trim_end().to_string();
+        failure(e.to_string(), stderr)
+    })?;
+    let stderr = String::from_utf8_lossy(&output.stderr)
+        .trim_end()
+        .to_string();
+    if !output.status.success() {
+        return Err(failure(format!("exited with {}", output.status), stderr));
+    }
+    if !stderr.is_empty() {
+        generated.log(
+            log::Level::Debug,
+            format!(
+                "Generator {} on {}: {}",
+                generator.name,
+                file.path.display(),
+                stderr
+            ),
+        );
+    }
+
+    read_output(generator, &new_content, &output.stdout).map_err(|e| failure(e.to_string(), stderr))
+}
+
+/// Check `output`, which `generator` made of `input`, against the
+/// specification. Problems `input` already had are not its fault, the others
+/// fail it if they are blocking at the configured `validation` and are
+/// logged otherwise.
+fn check_output(
+    config: &Config,
+    generator: &Generator,
+    file: &DesktopFile,
+    input: &str,
+    output: String,
+    generated: &mut Generated,
+) -> Result<String, Failure> {
+    let validation = config.validation.unwrap_or_default();
+    if validation == Validation::Off || output == input {
+        return Ok(output);
+    }
+
+    let known = spec::check(input, file.kind);
+    let mut blocking = Vec::new();
+    for problem in spec::check(&output, file.kind) {
+        if known.iter().any(|known| known.is_same(&problem)) {
+            continue;
+        }
+        if validation.is_blocking(problem.severity) {
+            blocking.push(problem.to_string());
+        } else {
+            generated.log(
+                log::Level::Warn,
+                format!(
+                    "Generator {} made {} invalid: {}",
+                    generator.name,
+                    file.path.display(),
+                    problem
+                ),
+            );
+        }
+    }
+
+    if blocking.is_empty() {
+        return Ok(output);
+    }
+    Err(Failure {
+        generator: generator.name.clone(),
+        file: file.path.clone(),
+        error: format!("invalid output, {}", blocking.join(", ")),
+        stderr: String::new(),
+    })
+}
+
+/// Print the generators matching `desktop_file` and the diff to the new
+/// content, if any, or the `on-error` a failing generator aborted it with.
+fn print_dry_run(
+    desktop_file: &Path,
+    new_path: &Path,
+    generators: &[&Generator],
+    new_content: Result<Option<&str>, OnError>,
+) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "{}: matched {}",
+        desktop_file.display(),
+        generators
+            .iter()
+            .map(|g| g.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )?;
+
+    let new_content = match new_content {
+        Ok(Some(new_content)) => new_content,
+        Ok(None) => {
+            writeln!(stdout, "(unchanged)")?;
+            return Ok(());
+        }
+        Err(on_error) => {
+            writeln!(
+                stdout,
+                "(aborted, a generator failed with on-error {})",
+                on_error
+            )?;
+            return Ok(());
+        }
+    };
+
+    let original = std::fs::read_to_string(desktop_file)?;
+    let Some(new_content) = finalize_desktop_file(new_content) else {
+        writeln!(stdout, "(no [Desktop Entry] group, skipped)")?;
+        return Ok(());
+    };
+    let color = stdout.is_terminal();
+    diff::write_unified_diff(
+        &mut stdout,
+        &original,
+        &new_content,
+        &desktop_file.display().to_string(),
+        &new_path.display().to_string(),
+        color,
+    )
 }
 
-fn apply_generator(command: &[String], input: &str) -> io::Result<std::process::Output> {
-    let mut cmd = ProcessCommand::new(&command[0]);
-    cmd.args(&command[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+fn apply_actions(generator: &Generator, input: &str) -> io::Result<String> {
+    let mut entry = DesktopEntry::parse(input)?;
 
-    let mut child = cmd.spawn()?;
-    {
-        let stdin = child
-            .stdin
-            .as_mut()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Failed to open stdin"))?;
-        stdin.write_all(input.as_bytes())?;
+    if let Some(group) = entry.group_mut(&generator.group) {
+        for (from, to) in &generator.rename {
+            group.rename(from, to);
+        }
+    }
+
+    if !generator.set.is_empty() {
+        let group = entry.ensure_group(&generator.group);
+        for (key, value) in &generator.set {
+            group.set(key, value);
+        }
+    }
+
+    if let Some(group) = entry.group_mut(&generator.group) {
+        for key in &generator.unset {
+            group.remove(key);
+        }
     }
 
-    let output = child.wait_with_output()?;
-    Ok(output)
+    Ok(entry.to_string())
+}
+
+fn apply_script(
+    script: &rhai::AST,
+    input: &str,
+    limits: &subprocess::Limits,
+) -> io::Result<script::Output> {
+    script::run(script, DesktopEntry::parse(input)?, limits)
 }
 
-fn write_new_desktop_file(original_path: &PathBuf, content: &str) -> io::Result<()> {
-    let xdg_data_home = env::var("XDG_DATA_HOME")
-        .unwrap_or_else(|_| format!("{}/.local/share", env::var("HOME").unwrap()));
-    let new_path = PathBuf::from(xdg_data_home)
-        .join("applications")
-        .join(original_path.file_name().unwrap());
+/// Run the command of `generator` on `input`, the content of `file`, sent as
+/// is or as JSON depending on its `protocol`. The command gets the file in
+/// the `XDFO_*` environment variables, and `{id}` and `{path}` in its
+/// arguments are replaced with its ID and path.
+fn apply_generator(
+    generator: &Generator,
+    file: &DesktopFile,
+    input: &str,
+) -> Result<std::process::Output, subprocess::Error> {
+    let command: Vec<OsString> = generator
+        .command
+        .iter()
+        .map(|arg| substitute_placeholders(arg, file))
+        .collect();
+    let input = match generator.protocol {
+        Protocol::Text => input.to_string(),
+        Protocol::Json => protocol::encode(&DesktopEntry::parse(input)?),
+    };
+    let origin = file.origin.to_string();
+    let envs = [
+        ("XDFO_DESKTOP_ID", OsStr::new(&file.id)),
+        ("XDFO_SOURCE_PATH", file.path.as_os_str()),
+        ("XDFO_DATA_DIR", file.data_dir.as_os_str()),
+        ("XDFO_GENERATOR_NAME", OsStr::new(&generator.name)),
+        ("XDFO_ORIGIN", OsStr::new(&origin)),
+    ];
+    subprocess::run(&command, &envs, input.as_bytes(), &generator.limits())
+}
 
-    let mut final_content = content.to_string();
+/// The new content from the `stdout` of `generator`, which ran on `input`.
+fn read_output(generator: &Generator, input: &str, stdout: &[u8]) -> io::Result<String> {
+    match generator.protocol {
+        Protocol::Text => Ok(String::from_utf8_lossy(stdout).to_string()),
+        Protocol::Json => Ok(protocol::decode(DesktopEntry::parse(input)?, stdout)?.to_string()),
+    }
+}
+
+/// Replace `{id}` and `{path}` in `arg` with the ID and path of `file`.
+fn substitute_placeholders(arg: &str, file: &DesktopFile) -> OsString {
+    let mut result = OsString::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        result.push(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(tail) = rest.strip_prefix("{id}") {
+            result.push(&file.id);
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("{path}") {
+            result.push(&file.path);
+            rest = tail;
+        } else {
+            result.push("{");
+            rest = &rest[1..];
+        }
+    }
+    result.push(rest);
+    result
+}
+
+/// Add the override marker to generated content, `None` if it has no
+/// `[Desktop Entry]` group to add it to. Content the parser rejects, e.g.
+/// because of keys not following the specification, gets the marker right
+/// after the group header instead of being refused.
+fn finalize_desktop_file(content: &str) -> Option<String> {
     let override_property = "X-XDG-Desktop-File-Override-Version";
-    if !final_content.contains(override_property) {
-        // Insert the property into the beginning of the main section
-        let main_section_start =
-            final_content.find("[Desktop Entry]").unwrap_or_else(|| 0) + "[Desktop Entry]".len();
-        final_content.insert_str(
-            main_section_start,
-            &format!("\n{}={}", override_property, env!("CARGO_PKG_VERSION")),
-        );
+    let marker = format!("{}={}", override_property, env!("CARGO_PKG_VERSION"));
+
+    let Ok(mut entry) = DesktopEntry::parse(content) else {
+        let mut lines: Vec<&str> = content.split('\n').collect();
+        let header_line = format!("[{}]", MAIN_GROUP);
+        let header = lines.iter().position(|line| line.trim() == header_line)?;
+        let prefix = format!("{}=", override_property);
+        if !lines.iter().any(|line| line.starts_with(&prefix)) {
+            lines.insert(header + 1, &marker);
+        }
+        return Some(lines.join("\n"));
+    };
+    let main_group = entry.group_mut(MAIN_GROUP)?;
+    if main_group.get(override_property).is_none() {
+        main_group.set(override_property, env!("CARGO_PKG_VERSION"));
     }
 
+    Some(entry.to_string())
+}
+
+/// Write the generated file, returning the written content. Existing files
+/// are never overwritten, as they are not generated by this program.
+fn write_new_desktop_file(new_path: &Path, content: &str) -> io::Result<Option<String>> {
+    let Some(content) = finalize_desktop_file(content) else {
+        warn!(
+            "Skip {:?}, which was generated without a [Desktop Entry] group",
+            new_path
+        );
+        return Ok(None);
+    };
+
     if new_path.exists() {
-        return Ok(());
+        warn!(
+            "Skip {:?}, which is not generated by this program",
+            new_path
+        );
+        return Ok(None);
     }
 
     info!(
         "Writing new desktop file {:?}",
-        original_path.file_name().unwrap()
+        new_path.file_name().unwrap()
     );
 
-    return std::fs::write(new_path, final_content);
+    if let Some(dir) = new_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(new_path, &content)?;
+    Ok(Some(content))
+}
+
+/// Remove the files recorded in `manifest`.
+fn clean_generated_files(manifest: &mut Manifest) -> io::Result<()> {
+    for (path, entry) in std::mem::take(&mut manifest.files) {
+        remove_generated_file(&path, &entry)?;
+    }
+    manifest.unchanged.clear();
+
+    Ok(())
+}
+
+/// Remove a generated file. Files changed since generation are kept, as they
+/// now belong to the user.
+fn remove_generated_file(path: &Path, entry: &ManifestEntry) -> io::Result<()> {
+    match entry.state(path)? {
+        FileState::Unchanged => {
+            debug!("Remove generated file {:?}", path);
+            std::fs::remove_file(path)
+        }
+        FileState::Modified => {
+            warn!("Keep {:?}, which was modified after it was generated", path);
+            Ok(())
+        }
+        FileState::Missing => Ok(()),
+    }
 }
 
-fn clean_generated_files() -> io::Result<()> {
-    let xdg_data_home = env::var("XDG_DATA_HOME")
-        .unwrap_or_else(|_| format!("{}/.local/share", env::var("HOME").unwrap()));
-    let applications_path = PathBuf::from(xdg_data_home).join("applications");
+fn clean_legacy_files(output_dir: &Path) -> io::Result<()> {
+    if !output_dir.exists() {
+        return Ok(());
+    }
 
-    for entry in std::fs::read_dir(applications_path)? {
+    for entry in std::fs::read_dir(output_dir)? {
         let entry = entry?;
         if entry.path().extension().and_then(|s| s.to_str()) == Some("desktop") {
-            let content = std::fs::read_to_string(&entry.path())?;
+            let content = std::fs::read_to_string(entry.path())?;
             if content.contains("X-XDG-Desktop-File-Override-Version") {
                 std::fs::remove_file(entry.path())?;
             }
@@ -210,48 +1352,506 @@ fn clean_generated_files() -> io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::{self, File};
+    use std::fs;
     use tempfile::tempdir;
 
+    /// Paths reading `config` and the applications in `dir/data`, writing to
+    /// `dir/out`.
+    fn temp_paths(dir: &Path, config: &str) -> Paths {
+        fs::create_dir_all(dir.join("data/applications")).unwrap();
+        fs::write(dir.join("config.yaml"), config).unwrap();
+        Paths {
+            config: Some(dir.join("config.yaml")),
+            data_dirs: Some(vec![dir.join("data")]),
+            output_dir: Some(dir.join("out")),
+        }
+    }
+
+    fn desktop_file() -> DesktopFile {
+        DesktopFile {
+            id: "kde4-foo.desktop".to_string(),
+            path: PathBuf::from("/usr/share/applications/kde4/foo.desktop"),
+            data_dir: PathBuf::from("/usr/share"),
+            kind: Kind::Application,
+            origin: discovery::Origin::System,
+        }
+    }
+
     #[test]
-    fn test_get_desktop_files() {
+    fn test_paths() {
+        let paths = Paths {
+            config: Some(PathBuf::from("/etc/override.yaml")),
+            data_dirs: Some(vec![PathBuf::from("/a"), PathBuf::from("/b")]),
+            output_dir: Some(PathBuf::from("/out")),
+        };
+        assert_eq!(
+            paths.source_dirs(Kind::Application),
+            [
+                PathBuf::from("/a/applications"),
+                PathBuf::from("/b/applications")
+            ]
+        );
+        assert_eq!(
+            paths.source_dirs(Kind::Directory),
+            [
+                PathBuf::from("/a/desktop-directories"),
+                PathBuf::from("/b/desktop-directories")
+            ]
+        );
+        assert_eq!(
+            paths.output_path(Kind::Application, "kde4-foo.desktop"),
+            Path::new("/out/applications/kde4-foo.desktop")
+        );
+        assert_eq!(
+            paths.output_path_of(Path::new("/b/applications/kde4/foo.desktop")),
+            Some(PathBuf::from("/out/applications/kde4-foo.desktop"))
+        );
+        assert_eq!(
+            paths.output_path_of(Path::new("/a/desktop-directories/games.directory")),
+            Some(PathBuf::from("/out/desktop-directories/games.directory"))
+        );
+        assert_eq!(
+            paths.manifest_path(),
+            Path::new("/out").join(OUTPUT_DIR_MANIFEST_FILE_NAME)
+        );
+        assert_eq!(
+            paths.options(),
+            [
+                "--config",
+                "/etc/override.yaml",
+                "--data-dirs",
+                "/a:/b",
+                "--output-dir",
+                "/out"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_sources_shadowing() {
         let dir = tempdir().unwrap();
-        let applications_path = dir.path().join("applications");
-        fs::create_dir_all(&applications_path).unwrap();
-        File::create(applications_path.join("test.desktop")).unwrap();
+        let paths = temp_paths(
+            dir.path(),
+            "version: 0.2.0\ngenerators:\n  - { name: a, unset: [A] }\n",
+        );
+        let config = load_config(&paths).unwrap().config;
+        for id in ["user.desktop", "generated.desktop", "other.desktop"] {
+            fs::write(dir.path().join("data/applications").join(id), "").unwrap();
+        }
+        let output_dir = paths.output_dir(Kind::Application);
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(output_dir.join("user.desktop"), "").unwrap();
+        fs::write(output_dir.join("generated.desktop"), "").unwrap();
 
-        env::set_var("XDG_DATA_DIRS", dir.path().to_str().unwrap());
-        let result = get_desktop_files().unwrap();
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0].file_name().unwrap(), "test.desktop");
+        let mut manifest = Manifest::default();
+        manifest.files.insert(
+            output_dir.join("generated.desktop"),
+            ManifestEntry {
+                source: dir.path().join("data/applications/generated.desktop"),
+                source_hash: manifest::hash(b""),
+                generators: vec!["a".to_string()],
+                output_hash: manifest::hash(b""),
+                fingerprint: String::new(),
+            },
+        );
+
+        // The file of the user hides its source, the generated one does not.
+        let ids: Vec<String> = find_sources(&paths, &config, &manifest)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.id)
+            .collect();
+        assert_eq!(ids, ["generated.desktop", "other.desktop"]);
     }
 
     #[test]
     fn test_apply_generator() {
-        let command = vec![
-            "sed".to_string(),
-            "-e".to_string(),
-            "s/foo/bar/".to_string(),
-        ];
+        let generator: Generator =
+            serde_yaml::from_str("{ name: test, command: [sed, -e, s/foo/bar/] }").unwrap();
         let input = "foo";
-        let output = apply_generator(&command, input).unwrap();
+        let output = apply_generator(&generator, &desktop_file(), input).unwrap();
         let result = String::from_utf8_lossy(&output.stdout);
         assert_eq!(result, "bar");
     }
 
+    #[test]
+    fn test_apply_generator_context() {
+        let generator: Generator = serde_yaml::from_str(
+            r#"
+name: context
+command:
+  - sh
+  - -c
+  - 'echo "$1 $2 {x}"; echo "$XDFO_DESKTOP_ID $XDFO_SOURCE_PATH $XDFO_DATA_DIR $XDFO_GENERATOR_NAME $XDFO_ORIGIN"'
+  - sh
+  - '{id}'
+  - 'path={path}'
+"#,
+        )
+        .unwrap();
+        let output = apply_generator(&generator, &desktop_file(), "").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "kde4-foo.desktop path=/usr/share/applications/kde4/foo.desktop {x}\n\
+             kde4-foo.desktop /usr/share/applications/kde4/foo.desktop /usr/share context system\n"
+        );
+    }
+
+    #[test]
+    fn test_generate_file_on_error() {
+        for (on_error, content, aborted) in [
+            (
+                "skip",
+                Some("[Desktop Entry]\nName=Foo\nComment=Hi\nKeywords=foo;\n"),
+                None,
+            ),
+            ("abort-file", None, Some(OnError::AbortFile)),
+            ("abort-all", None, Some(OnError::AbortAll)),
+        ] {
+            let config: Config = serde_yaml::from_str(&format!(
+                "version: 0.2.0
+generators:
+  - {{ name: comment, set: {{ Comment: Hi }} }}
+  - {{ name: fail, command: [sh, -c, 'echo broken >&2; exit 3'], on-error: {} }}
+  - {{ name: keywords, set: {{ Keywords: foo; }} }}
+",
+                on_error
+            ))
+            .unwrap();
+            let compiled = CompiledConfig::new(config).unwrap();
+            let generators: Vec<&Generator> = compiled.config.generators.iter().collect();
+
+            let generated = generate_file(
+                &compiled,
+                &generators,
+                &desktop_file(),
+                "[Desktop Entry]\nName=Foo\n",
+            );
+            assert_eq!(generated.content.as_deref(), content, "{}", on_error);
+            assert_eq!(generated.aborted, aborted, "{}", on_error);
+            assert_eq!(generated.failures.len(), 1);
+            assert_eq!(generated.failures[0].generator, "fail");
+            assert_eq!(generated.failures[0].stderr, "broken");
+        }
+    }
+
+    #[test]
+    fn test_generate_file_validation() {
+        let config: Config = serde_yaml::from_str(
+            "version: 0.2.0
+generators:
+  - { name: comment, set: { Comment: Hi } }
+  - { name: break-exec, set: { Exec: 'foo %x' } }
+  - { name: unset-name, unset: [Name] }
+",
+        )
+        .unwrap();
+        let compiled = CompiledConfig::new(config).unwrap();
+        let generators: Vec<&Generator> = compiled.config.generators.iter().collect();
+        let content = "[Desktop Entry]\nType=Application\nName=Foo\nExec=foo\n";
+
+        let generated = generate_file(&compiled, &generators, &desktop_file(), content);
+        assert_eq!(
+            generated.content.as_deref(),
+            Some("[Desktop Entry]\nType=Application\nName=Foo\nExec=foo\nComment=Hi\n")
+        );
+        let failures: Vec<_> = generated
+            .failures
+            .iter()
+            .map(|f| (f.generator.as_str(), f.error.as_str()))
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                (
+                    "break-exec",
+                    "invalid output, error: Exec in [Desktop Entry] has the invalid field code %x"
+                ),
+                (
+                    "unset-name",
+                    "invalid output, error: required key Name is missing"
+                ),
+            ]
+        );
+
+        // Problems of the source are not blamed on generators.
+        let content = "[Desktop Entry]\nType=Application\nExec=foo\n";
+        let generated = generate_file(&compiled, &generators[..1], &desktop_file(), content);
+        assert!(generated.failures.is_empty());
+
+        // Not even when a line inserted above moves them.
+        let config: Config = serde_yaml::from_str(
+            r"version: 0.2.0
+generators:
+  - { name: add-class, command: [sed, '/\[Desktop Entry\]/a StartupWMClass=x'] }
+",
+        )
+        .unwrap();
+        let compiled = CompiledConfig::new(config).unwrap();
+        let generators: Vec<&Generator> = compiled.config.generators.iter().collect();
+        let content = "[Desktop Entry]\nType=Application\nName=Foo\nExec=foo\nX-Foo_Bar=1\n";
+        let generated = generate_file(&compiled, &generators, &desktop_file(), content);
+        assert!(generated.failures.is_empty());
+        assert_eq!(
+            generated.content.as_deref(),
+            Some("[Desktop Entry]\nStartupWMClass=x\nType=Application\nName=Foo\nExec=foo\nX-Foo_Bar=1\n")
+        );
+    }
+
+    #[test]
+    fn test_apply_actions() {
+        let generator: Generator = serde_yaml::from_str(
+            r#"
+name: fix-zeditor
+filter: ^zed\.desktop$
+set:
+  StartupWMClass: dev.zed.Zed
+unset: [StartupNotify]
+rename:
+  X-Old: X-New
+"#,
+        )
+        .unwrap();
+        let input = "[Desktop Entry]\nName=Zed\nStartupNotify=true\nX-Old=1\n";
+        let result = apply_actions(&generator, input).unwrap();
+        assert_eq!(
+            result,
+            "[Desktop Entry]\nName=Zed\nX-New=1\nStartupWMClass=dev.zed.Zed\n"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut generator: Generator =
+            serde_yaml::from_str("{name: test, filter: '.*', unset: [StartupNotify]}").unwrap();
+        let before = fingerprint("[Desktop Entry]\n", &[&generator]);
+        assert_eq!(before, fingerprint("[Desktop Entry]\n", &[&generator]));
+        assert_ne!(
+            before,
+            fingerprint("[Desktop Entry]\nName=x\n", &[&generator])
+        );
+
+        generator.unset.push("DBusActivatable".to_string());
+        assert_ne!(before, fingerprint("[Desktop Entry]\n", &[&generator]));
+    }
+
     #[test]
     fn test_write_new_desktop_file() {
         let dir = tempdir().unwrap();
-        let applications_path = dir.path().join("applications");
-        fs::create_dir_all(&applications_path).unwrap();
-        let original_path = applications_path.join("test.desktop");
         let content = "[Desktop Entry]\nName=Test";
 
-        env::set_var("XDG_DATA_HOME", dir.path().to_str().unwrap());
-        write_new_desktop_file(&original_path, content).unwrap();
+        let new_path = dir.path().join("output/test.desktop");
+        write_new_desktop_file(&new_path, content).unwrap();
 
-        let new_path = applications_path.join("test.desktop");
         let result = fs::read_to_string(new_path).unwrap();
         assert!(result.contains("X-XDG-Desktop-File-Override-Version=0.1.0"));
     }
+
+    #[test]
+    fn test_finalize_desktop_file() {
+        assert_eq!(
+            finalize_desktop_file("[Desktop Entry]\nName=Test\n").as_deref(),
+            Some("[Desktop Entry]\nName=Test\nX-XDG-Desktop-File-Override-Version=0.1.0\n")
+        );
+        // Keys the parser rejects do not keep the file from being generated.
+        assert_eq!(
+            finalize_desktop_file("[Desktop Entry]\nName=Test\nX-Foo_Bar=1\n").as_deref(),
+            Some("[Desktop Entry]\nX-XDG-Desktop-File-Override-Version=0.1.0\nName=Test\nX-Foo_Bar=1\n")
+        );
+        assert_eq!(finalize_desktop_file("[Other]\nName=Test\n"), None);
+    }
+
+    #[test]
+    fn test_migrate_config() {
+        let dir = tempdir().unwrap();
+        let old = "# Mine\nversion: 0.1.0\ngenerators: []\n";
+        let paths = temp_paths(dir.path(), old);
+        let backup = dir.path().join("config.yaml.bak");
+
+        // An existing backup is never overwritten.
+        fs::write(&backup, "older").unwrap();
+        let err = migrate_config(&paths, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "older");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.yaml")).unwrap(),
+            old
+        );
+
+        fs::remove_file(&backup).unwrap();
+        migrate_config(&paths, false).unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), old);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.yaml")).unwrap(),
+            "# Mine\nversion: 0.2.0\ngenerators: []\n"
+        );
+    }
+
+    #[test]
+    fn test_clean_generated_files() {
+        let dir = tempdir().unwrap();
+        let generated = dir.path().join("generated.desktop");
+        let edited = dir.path().join("edited.desktop");
+        fs::write(&generated, "generated").unwrap();
+        fs::write(&edited, "edited by hand").unwrap();
+
+        let mut manifest = Manifest::default();
+        for path in [&generated, &edited, &dir.path().join("missing.desktop")] {
+            manifest.files.insert(
+                path.clone(),
+                ManifestEntry {
+                    source: PathBuf::from("/usr/share/applications/test.desktop"),
+                    source_hash: manifest::hash(b"source"),
+                    generators: Vec::new(),
+                    output_hash: manifest::hash(b"generated"),
+                    fingerprint: String::new(),
+                },
+            );
+        }
+
+        clean_generated_files(&mut manifest).unwrap();
+        assert!(!generated.exists());
+        assert!(edited.exists());
+        assert!(manifest.files.is_empty());
+    }
+
+    #[test]
+    fn test_generate_files_incremental() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("log");
+        let paths = temp_paths(
+            dir.path(),
+            &format!(
+                r#"version: 0.2.0
+generators:
+  - name: rename
+    command: [sh, -c, 'echo "$XDFO_DESKTOP_ID" >> {}; sed s/Old/New/']
+"#,
+                log.display()
+            ),
+        );
+        let source = |id: &str| dir.path().join("data/applications").join(id);
+        let output = |id: &str| paths.output_path(Kind::Application, id);
+        let write_source = |id: &str, name: &str| {
+            let content = format!("[Desktop Entry]\nType=Application\nName={}\nExec=a\n", name);
+            fs::write(source(id), content).unwrap();
+        };
+        write_source("a.desktop", "Old A");
+        write_source("b.desktop", "Old B");
+        write_source("c.desktop", "C");
+        write_source("d.desktop", "Old D");
+
+        let mut manifest = Manifest::default();
+        generate_files(&paths, false, 1, &mut manifest, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "a.desktop\nb.desktop\nc.desktop\nd.desktop\n"
+        );
+        assert!(!output("c.desktop").exists());
+        assert!(manifest.unchanged.contains_key(&source("c.desktop")));
+
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        fs::File::options()
+            .write(true)
+            .open(output("a.desktop"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        write_source("b.desktop", "Old B2");
+        fs::remove_file(source("d.desktop")).unwrap();
+        fs::remove_file(&log).unwrap();
+
+        generate_files(&paths, false, 1, &mut manifest, None).unwrap();
+        // Neither the up to date a nor the unchanged c are generated again.
+        assert_eq!(fs::read_to_string(&log).unwrap(), "b.desktop\n");
+        let modified = fs::metadata(output("a.desktop"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(modified, old);
+        assert!(fs::read_to_string(output("b.desktop"))
+            .unwrap()
+            .contains("Name=New B2\n"));
+        assert!(!output("d.desktop").exists());
+        assert!(!manifest.files.contains_key(&output("d.desktop")));
+        assert!(manifest.unchanged.contains_key(&source("c.desktop")));
+    }
+
+    #[test]
+    fn test_generate_files_on_error() {
+        let run = |dir: &Path, on_error: &str, failing: &str, manifest: &mut Manifest| {
+            let paths = temp_paths(
+                dir,
+                &format!(
+                    r#"version: 0.2.0
+on-error: {}
+generators:
+  - name: rename
+    command: [sh, -c, 'test "$XDFO_DESKTOP_ID" != {} || exit 3; sed s/Old/New/']
+"#,
+                    on_error, failing
+                ),
+            );
+            for id in ["a", "b", "c"] {
+                let source = dir.join(format!("data/applications/{}.desktop", id));
+                if !source.exists() {
+                    let content = format!(
+                        "[Desktop Entry]\nType=Application\nName=Old {}\nExec=a\n",
+                        id
+                    );
+                    fs::write(source, content).unwrap();
+                }
+            }
+            generate_files(&paths, false, 1, manifest, None)
+        };
+        let output = |dir: &Path, id: &str| dir.join(format!("out/applications/{}.desktop", id));
+
+        // A skipped generator leaves its file unchanged, to be retried.
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::default();
+        let result = run(dir.path(), "skip", "b.desktop", &mut manifest);
+        assert_eq!(result.unwrap_err().to_string(), "1 generators failed");
+        assert!(output(dir.path(), "a").exists());
+        assert!(!output(dir.path(), "b").exists());
+        assert!(output(dir.path(), "c").exists());
+        assert!(manifest.unchanged.is_empty());
+
+        // An aborted file keeps what an earlier run generated.
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::default();
+        run(dir.path(), "abort-file", "none", &mut manifest).unwrap();
+        fs::write(
+            dir.path().join("data/applications/b.desktop"),
+            "[Desktop Entry]\nType=Application\nName=Old b2\nExec=a\n",
+        )
+        .unwrap();
+        let result = run(dir.path(), "abort-file", "b.desktop", &mut manifest);
+        assert!(result.is_err());
+        let content = fs::read_to_string(output(dir.path(), "b")).unwrap();
+        assert!(content.contains("Name=New b\n"));
+        assert!(manifest.files.contains_key(&output(dir.path(), "b")));
+
+        // Nothing after an aborted run is generated or removed.
+        let dir = tempdir().unwrap();
+        let stale = output(dir.path(), "stale");
+        fs::create_dir_all(stale.parent().unwrap()).unwrap();
+        fs::write(&stale, "stale").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.files.insert(
+            stale.clone(),
+            ManifestEntry {
+                source: dir.path().join("data/applications/stale.desktop"),
+                source_hash: manifest::hash(b""),
+                generators: vec!["rename".to_string()],
+                output_hash: manifest::hash(b"stale"),
+                fingerprint: String::new(),
+            },
+        );
+        let result = run(dir.path(), "abort-all", "b.desktop", &mut manifest);
+        assert!(result.is_err());
+        assert!(output(dir.path(), "a").exists());
+        assert!(!output(dir.path(), "b").exists());
+        assert!(!output(dir.path(), "c").exists());
+        assert!(stale.exists());
+    }
 }
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Record of every desktop file written by `generate`.
+///
+/// `clean` only removes files listed here, and only if their content still
+/// matches what was generated.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+    version: u32,
+    /// Generated files keyed by their output path.
+    pub files: BTreeMap<PathBuf, ManifestEntry>,
+    /// Fingerprints of source files that matched generators which left them
+    /// unchanged, so they need not be processed again.
+    #[serde(default)]
+    pub unchanged: BTreeMap<PathBuf, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestEntry {
+    pub source: PathBuf,
+    pub source_hash: String,
+    pub generators: Vec<String>,
+    pub output_hash: String,
+    /// Hash of the source content and the definitions of the generators
+    /// applied, used to skip regenerating up to date files.
+    #[serde(default)]
+    pub fingerprint: String,
+}
+
+/// State of a generated file on disk compared to the manifest.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileState {
+    Unchanged,
+    Modified,
+    Missing,
+}
+
+impl Manifest {
+    /// Load the manifest at `path`, returning `None` if it does not exist.
+    pub fn load(path: &Path) -> io::Result<Option<Manifest>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let manifest: Manifest = serde_yaml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: unsupported manifest version {}",
+                    path.display(),
+                    manifest.version
+                ),
+            ));
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replace the manifest at `path`.
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        self.version = MANIFEST_VERSION;
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let content = serde_yaml::to_string(self).map_err(io::Error::other)?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(content.as_bytes())?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+impl ManifestEntry {
+    /// Compare the file at `path` with the recorded output hash.
+    pub fn state(&self, path: &Path) -> io::Result<FileState> {
+        match fs::read(path) {
+            Ok(content) if hash(&content) == self.output_hash => Ok(FileState::Unchanged),
+            Ok(_) => Ok(FileState::Modified),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FileState::Missing),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of `content`, prefixed with the algorithm name.
+pub fn hash(content: &[u8]) -> String {
+    let digest = Sha256::digest(content);
+    let mut result = String::from("sha256:");
+    for byte in digest {
+        result.push_str(&format!("{:02x}", byte));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state/manifest.yaml");
+        assert!(Manifest::load(&path).unwrap().is_none());
+
+        let mut manifest = Manifest::default();
+        manifest.files.insert(
+            dir.path().join("zed.desktop"),
+            ManifestEntry {
+                source: PathBuf::from("/usr/share/applications/zed.desktop"),
+                source_hash: hash(b"source"),
+                generators: vec!["fix-zeditor".to_string()],
+                output_hash: hash(b"output"),
+                fingerprint: hash(b"fingerprint"),
+            },
+        );
+        manifest.save(&path).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.version, MANIFEST_VERSION);
+        assert_eq!(loaded.files, manifest.files);
+    }
+
+    #[test]
+    fn test_state() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("zed.desktop");
+        fs::write(&path, "generated").unwrap();
+
+        let entry = ManifestEntry {
+            source: PathBuf::from("/usr/share/applications/zed.desktop"),
+            source_hash: hash(b"source"),
+            generators: Vec::new(),
+            output_hash: hash(b"generated"),
+            fingerprint: String::new(),
+        };
+        assert_eq!(entry.state(&path).unwrap(), FileState::Unchanged);
+
+        fs::write(&path, "edited by hand").unwrap();
+        assert_eq!(entry.state(&path).unwrap(), FileState::Modified);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(entry.state(&path).unwrap(), FileState::Missing);
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(
+            hash(b""),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
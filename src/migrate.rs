@@ -0,0 +1,152 @@
+use regex::Regex;
+use serde_yaml::Value;
+use std::fmt;
+
+/// Version of the config schema understood by this program.
+pub const CONFIG_VERSION: Version = Version(0, 2, 0);
+
+/// A migration from `from` to the next schema version.
+struct Migration {
+    from: Version,
+    to: Version,
+    apply: fn(&mut Value),
+}
+
+/// Every migration, ordered by version.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: Version(0, 1, 0),
+    to: Version(0, 2, 0),
+    // 0.2.0 only added fields and made `filter` optional.
+    apply: |_| {},
+}];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u64, pub u64, pub u64);
+
+impl Version {
+    pub fn parse(version: &str) -> Option<Version> {
+        let mut parts = version.trim().split('.');
+        let mut next = || -> Option<u64> {
+            match parts.next() {
+                Some(part) => part.parse().ok(),
+                None => Some(0),
+            }
+        };
+        let version = Version(next()?, next()?, next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Read the `version` field of a parsed config.
+pub fn get_version(config: &Value) -> Result<Version, String> {
+    let version = match config.get("version") {
+        Some(Value::String(version)) => version.clone(),
+        Some(Value::Number(version)) => version.to_string(),
+        Some(_) => return Err("`version` must be a string".to_string()),
+        None => return Err("missing field `version`".to_string()),
+    };
+    Version::parse(&version).ok_or_else(|| format!("invalid version {:?}", version))
+}
+
+/// Migrate `config` to [`CONFIG_VERSION`] in place. Returns the original
+/// version. Configs newer than this program are rejected.
+pub fn migrate(config: &mut Value) -> Result<Version, String> {
+    let original = get_version(config)?;
+    if original > CONFIG_VERSION {
+        return Err(format!(
+            "config version {} is newer than {} supported by this program, please upgrade it",
+            original, CONFIG_VERSION
+        ));
+    }
+
+    let mut version = original;
+    for migration in MIGRATIONS {
+        if version < migration.to {
+            if version < migration.from {
+                return Err(format!("config version {} is not supported", original));
+            }
+            (migration.apply)(config);
+            version = migration.to;
+        }
+    }
+
+    if let Value::Mapping(mapping) = config {
+        mapping.insert(
+            Value::String("version".to_string()),
+            Value::String(CONFIG_VERSION.to_string()),
+        );
+    }
+    Ok(original)
+}
+
+/// Rewrite the config `content` into the current schema. If the migrations
+/// only changed the version, just the `version` line is replaced so comments
+/// and formatting are kept. Returns the new content and whether it was
+/// possible to keep them.
+pub fn migrate_content(content: &str) -> Result<(String, bool), String> {
+    let original: Value = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+    let mut migrated = original.clone();
+    migrate(&mut migrated)?;
+
+    let version_line = Regex::new(r#"(?m)^(version:[ \t]*)("[^"\n]*"|'[^'\n]*'|[^#\s]+)"#).unwrap();
+    let edited = version_line
+        .replace(content, format!("${{1}}{}", CONFIG_VERSION))
+        .to_string();
+    if serde_yaml::from_str::<Value>(&edited).ok().as_ref() == Some(&migrated) {
+        return Ok((edited, true));
+    }
+
+    let serialized = serde_yaml::to_string(&migrated).map_err(|e| e.to_string())?;
+    Ok((serialized, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        assert_eq!(Version::parse("0.1.0"), Some(Version(0, 1, 0)));
+        assert_eq!(Version::parse("1"), Some(Version(1, 0, 0)));
+        assert_eq!(Version::parse("0.x"), None);
+        assert_eq!(Version::parse("1.2.3.4"), None);
+        assert!(Version(0, 10, 0) > Version(0, 9, 1));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut config: Value = serde_yaml::from_str("version: 0.1.0\ngenerators: []\n").unwrap();
+        assert_eq!(migrate(&mut config).unwrap(), Version(0, 1, 0));
+        assert_eq!(get_version(&config).unwrap(), CONFIG_VERSION);
+
+        let mut config: Value = serde_yaml::from_str("version: 99.0.0\ngenerators: []\n").unwrap();
+        assert!(migrate(&mut config).unwrap_err().contains("newer"));
+
+        let mut config: Value = serde_yaml::from_str("version: 0.0.1\ngenerators: []\n").unwrap();
+        assert!(migrate(&mut config).unwrap_err().contains("not supported"));
+
+        let mut config: Value = serde_yaml::from_str("generators: []\n").unwrap();
+        assert!(migrate(&mut config).unwrap_err().contains("missing"));
+    }
+
+    #[test]
+    fn test_migrate_content_keeps_comments() {
+        let content =
+            "# My overrides\nversion: '0.1.0' # schema\ngenerators:\n  # none yet\n  []\n";
+        let (migrated, kept) = migrate_content(content).unwrap();
+        assert!(kept);
+        assert_eq!(
+            migrated,
+            "# My overrides\nversion: 0.2.0 # schema\ngenerators:\n  # none yet\n  []\n"
+        );
+    }
+}
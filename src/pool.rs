@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Run `work` on every item of `items` in up to `jobs` threads, passing the
+/// results to `consume` on the calling thread in the order of `items`, no
+/// matter in which order they finish. Once `consume` breaks or fails, no
+/// more items are started and the results not consumed yet are dropped.
+pub fn for_each_ordered<T, R, W, C>(
+    items: &[T],
+    jobs: usize,
+    work: W,
+    mut consume: C,
+) -> io::Result<()>
+where
+    T: Sync,
+    R: Send,
+    W: Fn(&T) -> R + Sync,
+    C: FnMut(usize, R) -> io::Result<ControlFlow<()>>,
+{
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let sender = sender.clone();
+            let (next, stop, work) = (&next, &stop, &work);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else {
+                        break;
+                    };
+                    if sender.send((index, work(item))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&expected) {
+                let flow = consume(expected, result);
+                expected += 1;
+                if !matches!(flow, Ok(ControlFlow::Continue(()))) {
+                    stop.store(true, Ordering::Relaxed);
+                    return flow.map(|_| ());
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_for_each_ordered() {
+        let items: Vec<u64> = (0..20).collect();
+        let mut consumed = Vec::new();
+        for_each_ordered(
+            &items,
+            4,
+            |item| {
+                // Let later items finish first.
+                thread::sleep(Duration::from_millis(20 - item));
+                item * 2
+            },
+            |index, result| {
+                consumed.push((index, result));
+                Ok(ControlFlow::Continue(()))
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            consumed,
+            (0..20).map(|i| (i as usize, i * 2)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_for_each_ordered_break() {
+        let items: Vec<u64> = (0..100).collect();
+        let mut consumed = Vec::new();
+        for_each_ordered(
+            &items,
+            2,
+            |item| *item,
+            |index, result| {
+                consumed.push(result);
+                Ok(if index == 3 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                })
+            },
+        )
+        .unwrap();
+        assert_eq!(consumed, [0, 1, 2, 3]);
+    }
+}
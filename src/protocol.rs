@@ -0,0 +1,352 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+
+use crate::config::default_group;
+use crate::desktop_entry::{split_key, unescape, DesktopEntry};
+
+/// A desktop entry as sent to and accepted from generators speaking JSON.
+/// Values are unescaped, and localized values are entries with a `locale`.
+///
+/// ```json
+/// {"groups": [{"name": "Desktop Entry", "entries": [
+///   {"key": "Name", "value": "Steam"},
+///   {"key": "Name", "locale": "de", "value": "Dampf"}
+/// ]}]}
+/// ```
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEntry {
+    groups: Vec<JsonGroup>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct JsonGroup {
+    name: String,
+    entries: Vec<JsonValue>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct JsonValue {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+    value: String,
+}
+
+/// An edit a generator speaking JSON may return instead of a whole entry.
+/// `group` defaults to `Desktop Entry`.
+///
+/// ```json
+/// [{"op": "set", "key": "Name", "locale": "de", "value": "Dampf"},
+///  {"op": "remove", "key": "DBusActivatable"},
+///  {"op": "rename", "key": "X-Old", "to": "X-New"},
+///  {"op": "remove-group", "group": "Desktop Action new-window"}]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+enum Edit {
+    Set {
+        #[serde(default = "default_group")]
+        group: String,
+        key: String,
+        locale: Option<String>,
+        value: String,
+    },
+    Remove {
+        #[serde(default = "default_group")]
+        group: String,
+        key: String,
+        locale: Option<String>,
+    },
+    Rename {
+        #[serde(default = "default_group")]
+        group: String,
+        key: String,
+        to: String,
+    },
+    RemoveGroup {
+        group: String,
+    },
+}
+
+/// Serialize `entry` as the JSON sent to a generator on stdin.
+pub fn encode(entry: &DesktopEntry) -> String {
+    let json = JsonEntry {
+        groups: entry
+            .groups()
+            .map(|group| JsonGroup {
+                name: group.name().to_string(),
+                entries: group
+                    .entries()
+                    .map(|entry| JsonValue {
+                        key: entry.base_key().to_string(),
+                        locale: entry.locale().map(str::to_string),
+                        value: unescape(entry.value()),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    serde_json::to_string(&json).unwrap()
+}
+
+/// Apply the `output` of a generator to `entry`: either a whole entry, which
+/// replaces it while keeping the lines whose value did not change, or a list
+/// of edits. Invalid group names, keys and locales are rejected.
+pub fn decode(mut entry: DesktopEntry, output: &[u8]) -> io::Result<DesktopEntry> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let value: serde_json::Value = serde_json::from_slice(output)
+        .map_err(|e| invalid(format!("invalid JSON output: {}", e)))?;
+    if value.is_array() {
+        let edits: Vec<Edit> =
+            serde_json::from_value(value).map_err(|e| invalid(format!("invalid edits: {}", e)))?;
+        for edit in edits {
+            apply_edit(&mut entry, edit).map_err(invalid)?;
+        }
+    } else {
+        let json: JsonEntry =
+            serde_json::from_value(value).map_err(|e| invalid(format!("invalid entry: {}", e)))?;
+        replace(&mut entry, json).map_err(invalid)?;
+    }
+    Ok(entry)
+}
+
+fn apply_edit(entry: &mut DesktopEntry, edit: Edit) -> Result<(), String> {
+    match edit {
+        Edit::Set {
+            group,
+            key,
+            locale,
+            value,
+        } => {
+            check_group(&group)?;
+            let key = full_key(&key, locale.as_deref())?;
+            entry.ensure_group(&group).set_string(&key, &value);
+        }
+        Edit::Remove { group, key, locale } => {
+            let key = full_key(&key, locale.as_deref())?;
+            if let Some(group) = entry.group_mut(&group) {
+                group.remove(&key);
+            }
+        }
+        Edit::Rename { group, key, to } => {
+            check_key(&key)?;
+            check_key(&to)?;
+            if let Some(group) = entry.group_mut(&group) {
+                group.rename(&key, &to);
+            }
+        }
+        Edit::RemoveGroup { group } => {
+            entry.remove_group(&group);
+        }
+    }
+    Ok(())
+}
+
+/// Make `entry` hold exactly what `json` holds, leaving the lines of
+/// unchanged values and comments in the kept groups as they are.
+fn replace(entry: &mut DesktopEntry, json: JsonEntry) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for group in &json.groups {
+        check_group(&group.name)?;
+        if !names.insert(group.name.as_str()) {
+            return Err(format!("duplicate group {:?}", group.name));
+        }
+    }
+
+    let removed: Vec<String> = entry
+        .groups()
+        .map(|group| group.name().to_string())
+        .filter(|name| !names.contains(name.as_str()))
+        .collect();
+    for name in removed {
+        entry.remove_group(&name);
+    }
+
+    for json_group in json.groups {
+        let mut keys = HashSet::new();
+        let mut values = Vec::new();
+        for value in json_group.entries {
+            let key = full_key(&value.key, value.locale.as_deref())?;
+            if !keys.insert(key.clone()) {
+                return Err(format!(
+                    "duplicate key {:?} in group {:?}",
+                    key, json_group.name
+                ));
+            }
+            values.push((key, value.value));
+        }
+
+        let group = entry.ensure_group(&json_group.name);
+        let removed: Vec<String> = group
+            .entries()
+            .map(|entry| entry.key().to_string())
+            .filter(|key| !keys.contains(key))
+            .collect();
+        for key in removed {
+            group.remove(&key);
+        }
+        for (key, value) in values {
+            if group.get_string(&key).as_deref() != Some(value.as_str()) {
+                group.set_string(&key, &value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `key` with the `locale` suffix, if any, after checking both are valid.
+fn full_key(key: &str, locale: Option<&str>) -> Result<String, String> {
+    check_key(key)?;
+    match locale {
+        Some(locale) => {
+            if locale.is_empty()
+                || !locale
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.@-".contains(c))
+            {
+                return Err(format!("invalid locale {:?} of key {:?}", locale, key));
+            }
+            Ok(format!("{}[{}]", key, locale))
+        }
+        None => Ok(key.to_string()),
+    }
+}
+
+/// Keys may only contain `A-Za-z0-9-`, a locale is given separately.
+fn check_key(key: &str) -> Result<(), String> {
+    let (base, locale) = split_key(key);
+    if locale.is_some()
+        || base.is_empty()
+        || !base.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(format!("invalid key {:?}", key));
+    }
+    Ok(())
+}
+
+/// Group names may contain any ASCII character except `[`, `]` and control
+/// characters.
+fn check_group(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii() && !c.is_ascii_control() && c != '[' && c != ']')
+    {
+        return Err(format!("invalid group name {:?}", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = "[Desktop Entry]\n\
+# Kept\n\
+Name=Steam\n\
+Name[de]=Dampf\n\
+Exec=steam %U\n\
+Comment=Play\\sgames\n\
+\n\
+[Desktop Action library]\n\
+Name=Library\n";
+
+    fn entry() -> DesktopEntry {
+        DesktopEntry::parse(ENTRY).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = encode(&entry());
+        assert!(json.contains(r#"{"key":"Name","locale":"de","value":"Dampf"}"#));
+        assert!(json.contains(r#"{"key":"Comment","value":"Play games"}"#));
+        assert_eq!(decode(entry(), json.as_bytes()).unwrap().to_string(), ENTRY);
+    }
+
+    #[test]
+    fn test_replace() {
+        let output = r#"{"groups": [{"name": "Desktop Entry", "entries": [
+            {"key": "Name", "value": "Steam"},
+            {"key": "Exec", "value": "steam -silent %U"},
+            {"key": "Comment", "value": "Play games"},
+            {"key": "Keywords", "locale": "fr", "value": "jeux;vapeur;"}
+        ]}]}"#;
+        assert_eq!(
+            decode(entry(), output.as_bytes()).unwrap().to_string(),
+            "[Desktop Entry]\n\
+# Kept\n\
+Name=Steam\n\
+Exec=steam -silent %U\n\
+Comment=Play\\sgames\n\
+Keywords[fr]=jeux;vapeur;\n\
+\n"
+        );
+    }
+
+    #[test]
+    fn test_edits() {
+        let output = r#"[
+            {"op": "set", "key": "Name", "locale": "fr", "value": "Vapeur"},
+            {"op": "set", "key": "Comment", "value": "Line\nbreak"},
+            {"op": "remove", "key": "Name", "locale": "de"},
+            {"op": "rename", "key": "Exec", "to": "TryExec"},
+            {"op": "remove-group", "group": "Desktop Action library"}
+        ]"#;
+        assert_eq!(
+            decode(entry(), output.as_bytes()).unwrap().to_string(),
+            "[Desktop Entry]\n\
+# Kept\n\
+Name=Steam\n\
+TryExec=steam %U\n\
+Comment=Line\\nbreak\n\
+Name[fr]=Vapeur\n\
+\n"
+        );
+    }
+
+    #[test]
+    fn test_escaped_semicolons() {
+        let entry = || DesktopEntry::parse("[Desktop Entry]\nKeywords=Semi\\;colon;\n").unwrap();
+        let json = encode(&entry());
+        assert!(json.contains(r#"{"key":"Keywords","value":"Semi\\;colon;"}"#));
+
+        let replaced = json.replace("colon;", "colon;Other;");
+        let edit = r#"[{"op": "set", "key": "Keywords", "value": "Semi\\;colon;Other;"}]"#;
+        for output in [replaced.as_str(), edit] {
+            let decoded = decode(entry(), output.as_bytes()).unwrap();
+            assert_eq!(
+                decoded.to_string(),
+                "[Desktop Entry]\nKeywords=Semi\\;colon;Other;\n"
+            );
+            assert_eq!(
+                decoded
+                    .group("Desktop Entry")
+                    .unwrap()
+                    .get_list("Keywords")
+                    .unwrap(),
+                ["Semi;colon", "Other"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_output() {
+        for output in [
+            "not json",
+            r#"{"groups": [{"name": "Bad]", "entries": []}]}"#,
+            r#"{"groups": [{"name": "G", "entries": [{"key": "A B", "value": ""}]}]}"#,
+            r#"{"groups": [{"name": "G", "entries": [
+                {"key": "A", "value": ""}, {"key": "A", "value": ""}]}]}"#,
+            r#"[{"op": "set", "key": "Name", "locale": "d e", "value": ""}]"#,
+            r#"[{"op": "delete", "key": "Name"}]"#,
+        ] {
+            let err = decode(entry(), output.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", output);
+        }
+    }
+}
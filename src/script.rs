@@ -0,0 +1,349 @@
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::desktop_entry::{join_list, DesktopEntry, MAIN_GROUP};
+use crate::subprocess::Limits;
+
+/// The result of running a script on a desktop entry.
+#[derive(Debug)]
+pub struct Output {
+    pub entry: DesktopEntry,
+    /// Lines the script printed with `print` or `debug`.
+    pub log: Vec<String>,
+}
+
+/// Compile `source` once, to be run on every matching desktop file.
+pub fn compile(source: &str) -> io::Result<AST> {
+    engine()
+        .compile(source)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/// Run `script` on `entry`, which it gets as the variable `entry`. The entry
+/// is taken from the value of the script if that is an entry, from the
+/// variable otherwise. Scripts have no access to the filesystem, and are
+/// stopped once they run longer than `limits.timeout` or build strings or
+/// arrays larger than `limits.max_output`.
+pub fn run(script: &AST, entry: DesktopEntry, limits: &Limits) -> io::Result<Output> {
+    let mut engine = engine();
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let printed = Arc::clone(&log);
+    engine.on_print(move |line| printed.lock().unwrap().push(line.to_string()));
+    let printed = Arc::clone(&log);
+    engine.on_debug(move |line, _, _| printed.lock().unwrap().push(line.to_string()));
+
+    if let Some(timeout) = limits.timeout {
+        let deadline = Instant::now() + timeout;
+        engine.on_progress(move |_| (Instant::now() >= deadline).then_some(Dynamic::UNIT));
+    }
+    if let Some(max_output) = limits.max_output {
+        engine.set_max_string_size(max_output);
+        engine.set_max_array_size(max_output);
+    }
+
+    let mut scope = Scope::new();
+    scope.push("entry", entry);
+    let result = engine
+        .eval_ast_with_scope::<Dynamic>(&mut scope, script)
+        .map_err(|e| match *e {
+            EvalAltResult::ErrorTerminated(..) => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "timed out after {:?}, stopped",
+                    limits.timeout.unwrap_or_default()
+                ),
+            ),
+            e => io::Error::other(e.to_string()),
+        })?;
+
+    let entry = match result.try_cast::<DesktopEntry>() {
+        Some(entry) => entry,
+        None => scope
+            .get_value::<DesktopEntry>("entry")
+            .ok_or_else(|| io::Error::other("variable entry is no desktop entry anymore"))?,
+    };
+    let log = std::mem::take(&mut *log.lock().unwrap());
+    Ok(Output { entry, log })
+}
+
+/// An engine with the helpers on desktop entries registered, which cannot
+/// load modules and so cannot read any file.
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.register_type_with_name::<DesktopEntry>("Entry");
+
+    engine.register_fn("get", |entry: &mut DesktopEntry, key: &str| {
+        get(entry, MAIN_GROUP, key)
+    });
+    engine.register_fn("get", get);
+    engine.register_fn(
+        "get_localized",
+        |entry: &mut DesktopEntry, key: &str, locale: &str| {
+            get_localized(entry, MAIN_GROUP, key, locale)
+        },
+    );
+    engine.register_fn("get_localized", get_localized);
+    engine.register_fn("get_list", |entry: &mut DesktopEntry, key: &str| {
+        get_list(entry, MAIN_GROUP, key)
+    });
+    engine.register_fn("get_list", get_list);
+    engine.register_fn("has", |entry: &mut DesktopEntry, key: &str| {
+        has(entry, MAIN_GROUP, key)
+    });
+    engine.register_fn("has", has);
+    engine.register_fn("set", |entry: &mut DesktopEntry, key: &str, value: &str| {
+        set(entry, MAIN_GROUP, key, value)
+    });
+    engine.register_fn("set", set);
+    engine.register_fn(
+        "set_localized",
+        |entry: &mut DesktopEntry, key: &str, locale: &str, value: &str| {
+            set(entry, MAIN_GROUP, &localized(key, locale), value)
+        },
+    );
+    engine.register_fn(
+        "set_localized",
+        |entry: &mut DesktopEntry, group: &str, key: &str, locale: &str, value: &str| {
+            set(entry, group, &localized(key, locale), value)
+        },
+    );
+    engine.register_fn(
+        "set_list",
+        |entry: &mut DesktopEntry, key: &str, values: Array| {
+            set_list(entry, MAIN_GROUP, key, values)
+        },
+    );
+    engine.register_fn("set_list", set_list);
+    engine.register_fn("remove", |entry: &mut DesktopEntry, key: &str| {
+        remove(entry, MAIN_GROUP, key)
+    });
+    engine.register_fn("remove", remove);
+    engine.register_fn("keys", |entry: &mut DesktopEntry| keys(entry, MAIN_GROUP));
+    engine.register_fn("keys", keys);
+    engine.register_fn("groups", |entry: &mut DesktopEntry| -> Array {
+        entry
+            .groups()
+            .map(|group| Dynamic::from(group.name().to_string()))
+            .collect()
+    });
+    engine.register_fn("has_group", |entry: &mut DesktopEntry, group: &str| {
+        entry.group(group).is_some()
+    });
+    engine.register_fn("remove_group", |entry: &mut DesktopEntry, group: &str| {
+        entry.remove_group(group)
+    });
+    engine.register_fn("actions", |entry: &mut DesktopEntry| -> Array {
+        get_list(entry, MAIN_GROUP, "Actions")
+    });
+    engine.register_fn("action_group", |action: &str| {
+        format!("Desktop Action {}", action)
+    });
+
+    engine
+}
+
+/// Unescaped value of `key` in `group`, `()` if it is not set.
+fn get(entry: &mut DesktopEntry, group: &str, key: &str) -> Dynamic {
+    entry
+        .group(group)
+        .and_then(|group| group.get_string(key))
+        .map_or(Dynamic::UNIT, Dynamic::from)
+}
+
+/// Value of `key` in `group` for `locale`, falling back the way the
+/// specification describes.
+fn get_localized(entry: &mut DesktopEntry, group: &str, key: &str, locale: &str) -> Dynamic {
+    entry
+        .group(group)
+        .and_then(|group| group.get_localized(key, locale))
+        .map_or(Dynamic::UNIT, Dynamic::from)
+}
+
+fn get_list(entry: &mut DesktopEntry, group: &str, key: &str) -> Array {
+    entry
+        .group(group)
+        .and_then(|group| group.get_list(key))
+        .unwrap_or_default()
+        .into_iter()
+        .map(Dynamic::from)
+        .collect()
+}
+
+fn has(entry: &mut DesktopEntry, group: &str, key: &str) -> bool {
+    entry
+        .group(group)
+        .is_some_and(|group| group.get(key).is_some())
+}
+
+/// Escape `value` and store it under `key` in `group`, adding the group if
+/// it is missing.
+fn set(entry: &mut DesktopEntry, group: &str, key: &str, value: &str) {
+    entry.ensure_group(group).set_string(key, value);
+}
+
+fn set_list(entry: &mut DesktopEntry, group: &str, key: &str, values: Array) {
+    let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+    entry.ensure_group(group).set(key, &join_list(&values));
+}
+
+/// Remove `key` from `group`, returning whether it was set.
+fn remove(entry: &mut DesktopEntry, group: &str, key: &str) -> bool {
+    entry
+        .group_mut(group)
+        .is_some_and(|group| group.remove(key))
+}
+
+/// Keys in `group`, including those with a locale suffix.
+fn keys(entry: &mut DesktopEntry, group: &str) -> Array {
+    entry
+        .group(group)
+        .map(|group| {
+            group
+                .entries()
+                .map(|entry| Dynamic::from(entry.key().to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn localized(key: &str, locale: &str) -> String {
+    format!("{}[{}]", key, locale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ENTRY: &str = "[Desktop Entry]\n\
+Name=Steam\n\
+Name[de]=Dampf\n\
+Exec=steam %U\n\
+Categories=Network;Game;\n\
+Actions=library;\n\
+\n\
+[Desktop Action library]\n\
+Name=Library\n";
+
+    fn run_script(source: &str) -> io::Result<Output> {
+        run_script_on(&DesktopEntry::parse(ENTRY).unwrap(), source)
+    }
+
+    fn run_script_on(entry: &DesktopEntry, source: &str) -> io::Result<Output> {
+        let script = compile(source)?;
+        run(
+            &script,
+            entry.clone(),
+            &Limits {
+                timeout: Some(Duration::from_secs(5)),
+                max_output: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_helpers() {
+        let output = run_script(
+            r#"
+            entry.set("Comment", "Play; games");
+            entry.remove("Exec");
+            if entry.get_localized("Name", "de_DE") == "Dampf" {
+                entry.set_localized("Name", "fr", "Vapeur");
+            }
+            let categories = entry.get_list("Categories");
+            categories.push("Steam");
+            entry.set_list("Categories", categories);
+            for action in entry.actions() {
+                let group = action_group(action);
+                entry.set(group, "Name", entry.get(group, "Name") + "!");
+            }
+            print(entry.keys().len());
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            output.entry.to_string(),
+            "[Desktop Entry]\n\
+Name=Steam\n\
+Name[de]=Dampf\n\
+Categories=Network;Game;Steam;\n\
+Actions=library;\n\
+Comment=Play; games\n\
+Name[fr]=Vapeur\n\
+\n\
+[Desktop Action library]\n\
+Name=Library!\n"
+        );
+        assert_eq!(output.log, ["6"]);
+    }
+
+    #[test]
+    fn test_escaped_semicolons() {
+        let entry = DesktopEntry::parse("[Desktop Entry]\nKeywords=Semi\\;colon;\n").unwrap();
+        let run = |script: &str| {
+            run_script_on(&entry, script)
+                .unwrap()
+                .entry
+                .group("Desktop Entry")
+                .unwrap()
+                .get_list("Keywords")
+                .unwrap()
+        };
+        assert_eq!(
+            run(r#"entry.set("Keywords", entry.get("Keywords") + "Other;");"#),
+            ["Semi;colon", "Other"]
+        );
+        assert_eq!(
+            run(r#"let keywords = entry.get_list("Keywords");
+                keywords.push("a\\;b");
+                entry.set_list("Keywords", keywords);"#),
+            ["Semi;colon", "a\\;b"]
+        );
+    }
+
+    #[test]
+    fn test_returned_entry() {
+        let output = run_script(
+            r#"
+            let copy = entry;
+            copy.set("Hidden", "true");
+            copy
+            "#,
+        )
+        .unwrap();
+        assert!(output.entry.to_string().contains("Hidden=true\n"));
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let err = run_script(r#"import "/etc/passwd" as passwd;"#).unwrap_err();
+        assert!(err.to_string().contains("/etc/passwd"));
+        assert!(run_script(r#"open_file("/etc/passwd")"#).is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let script = compile("loop {}").unwrap();
+        let err = run(
+            &script,
+            DesktopEntry::parse(ENTRY).unwrap(),
+            &Limits {
+                timeout: Some(Duration::from_millis(100)),
+                max_output: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let err = compile("entry.set(").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
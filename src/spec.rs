@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::desktop_entry::{DesktopEntry, Group, ParseError, MAIN_GROUP};
+use crate::discovery::Kind;
+
+/// Keys whose value must be a boolean.
+const BOOLEAN_KEYS: &[&str] = &[
+    "NoDisplay",
+    "Hidden",
+    "DBusActivatable",
+    "Terminal",
+    "StartupNotify",
+    "PrefersNonDefaultGPU",
+    "SingleMainWindow",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Allowed by the specification but deprecated or unusual.
+    Warning,
+    /// Violates the specification, launchers may ignore or misinterpret the
+    /// file.
+    Error,
+}
+
+/// A way a desktop file does not follow the Desktop Entry Specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Line the problem is on, if it is about a single one.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    /// Whether `other` is the same problem, possibly on another line, as
+    /// happens when lines are inserted or removed above it.
+    pub fn is_same(&self, other: &Problem) -> bool {
+        self.severity == other.severity && self.message == other.message
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.line {
+            Some(line) => write!(f, "{}: line {}: {}", severity, line, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// Check `content`, a desktop file of `kind`, against the Desktop Entry
+/// Specification, much like `desktop-file-validate` does.
+pub fn check(content: &str, kind: Kind) -> Vec<Problem> {
+    let mut checker = Checker::default();
+    match DesktopEntry::parse(content) {
+        Ok(entry) => checker.check_entry(&entry, kind),
+        Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<ParseError>()) {
+            Some(e) => checker.problems.push(Problem {
+                severity: Severity::Error,
+                line: Some(e.line),
+                message: format!("cannot be parsed: {}", e.message),
+            }),
+            None => checker.error(format!("cannot be parsed: {}", e)),
+        },
+    }
+    checker.problems
+}
+
+#[derive(Default)]
+struct Checker {
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn error(&mut self, message: String) {
+        self.problems.push(Problem {
+            severity: Severity::Error,
+            line: None,
+            message,
+        });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.problems.push(Problem {
+            severity: Severity::Warning,
+            line: None,
+            message,
+        });
+    }
+
+    fn check_entry(&mut self, entry: &DesktopEntry, kind: Kind) {
+        let mut names = HashSet::new();
+        for group in entry.groups() {
+            if !names.insert(group.name()) {
+                self.error(format!("group [{}] appears more than once", group.name()));
+            }
+            self.check_keys(group);
+        }
+
+        let Some(main) = entry.group(MAIN_GROUP) else {
+            self.error(format!("[{}] group is missing", MAIN_GROUP));
+            return;
+        };
+        if entry.groups().next().map(Group::name) != Some(MAIN_GROUP) {
+            self.error(format!("[{}] is not the first group", MAIN_GROUP));
+        }
+
+        for key in BOOLEAN_KEYS {
+            if let Some(value) = main.get(key) {
+                if value != "true" && value != "false" {
+                    self.error(format!("value {:?} of {} is not a boolean", value, key));
+                }
+            }
+        }
+
+        if main.get("Name").is_none() {
+            self.error("required key Name is missing".to_string());
+        }
+        let Some(type_) = main.get("Type") else {
+            self.error("required key Type is missing".to_string());
+            return;
+        };
+        match (type_, kind) {
+            ("Directory", Kind::Directory) => {}
+            ("Application" | "Link", Kind::Application | Kind::Autostart) => {}
+            ("Application" | "Link" | "Directory", _) => {
+                self.warning(format!("Type {} is unusual for this kind of file", type_))
+            }
+            _ => self.error(format!("Type {:?} is not a known type", type_)),
+        }
+
+        match type_ {
+            "Application" => {
+                let dbus_activatable = main.get("DBusActivatable") == Some("true");
+                match main.get_string("Exec") {
+                    Some(exec) => self.check_exec(MAIN_GROUP, &exec),
+                    None if !dbus_activatable => {
+                        self.error("Exec is required for Type Application".to_string())
+                    }
+                    None => {}
+                }
+                self.check_actions(entry, main, dbus_activatable);
+            }
+            "Link" if main.get("URL").is_none() => {
+                self.error("URL is required for Type Link".to_string())
+            }
+            _ => {}
+        }
+    }
+
+    /// Keys must appear only once per group. Invalid keys are already
+    /// rejected by the parser.
+    fn check_keys(&mut self, group: &Group) {
+        let mut keys = HashSet::new();
+        for entry in group.entries() {
+            if !keys.insert(entry.key()) {
+                self.error(format!(
+                    "key {} appears more than once in [{}]",
+                    entry.key(),
+                    group.name()
+                ));
+            }
+        }
+    }
+
+    /// Every action listed in `Actions` needs a group with a `Name`.
+    fn check_actions(&mut self, entry: &DesktopEntry, main: &Group, dbus_activatable: bool) {
+        for action in main.get_list("Actions").unwrap_or_default() {
+            let name = format!("Desktop Action {}", action);
+            let Some(group) = entry.group(&name) else {
+                self.error(format!("action {} has no [{}] group", action, name));
+                continue;
+            };
+            if group.get("Name").is_none() {
+                self.error(format!("required key Name is missing in [{}]", name));
+            }
+            match group.get_string("Exec") {
+                Some(exec) => self.check_exec(&name, &exec),
+                None if !dbus_activatable => self.error(format!("Exec is missing in [{}]", name)),
+                None => {}
+            }
+        }
+    }
+
+    /// Check the quoting and field codes of the unescaped `exec`.
+    fn check_exec(&mut self, group: &str, exec: &str) {
+        let mut file_codes = 0;
+        let mut quoted = false;
+        let mut chars = exec.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => {
+                    let escaped = chars.next();
+                    if !matches!(escaped, Some('"' | '`' | '$' | '\\')) {
+                        self.error(format!(
+                            "Exec in [{}] has an invalid escape in a quoted argument",
+                            group
+                        ));
+                    }
+                }
+                '%' => {
+                    let code = chars.next();
+                    if quoted && code != Some('%') {
+                        self.error(format!(
+                            "Exec in [{}] has a field code inside a quoted argument",
+                            group
+                        ));
+                    }
+                    match code {
+                        Some('f' | 'F' | 'u' | 'U') => file_codes += 1,
+                        Some('i' | 'c' | 'k' | '%') => {}
+                        Some(code @ ('d' | 'D' | 'n' | 'N' | 'v' | 'm')) => self.warning(format!(
+                            "Exec in [{}] has the deprecated field code %{}",
+                            group, code
+                        )),
+                        Some(code) => self.error(format!(
+                            "Exec in [{}] has the invalid field code %{}",
+                            group, code
+                        )),
+                        None => self.error(format!("Exec in [{}] ends with a lone %", group)),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if quoted {
+            self.error(format!("Exec in [{}] has an unterminated quote", group));
+        }
+        if file_codes > 1 {
+            self.error(format!(
+                "Exec in [{}] has more than one of %f, %F, %u and %U",
+                group
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(content: &str, kind: Kind) -> Vec<String> {
+        check(content, kind)
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let content = "[Desktop Entry]\n\
+Type=Application\n\
+Name=Steam\n\
+Exec=\"/usr/bin/steam\" -silent %U\n\
+Terminal=false\n\
+Actions=library;\n\
+\n\
+[Desktop Action library]\n\
+Name=Library\n\
+Exec=steam steam://open/games\n";
+        assert!(check(content, Kind::Application).is_empty());
+
+        let content = "[Desktop Entry]\nType=Directory\nName=Games\n";
+        assert!(check(content, Kind::Directory).is_empty());
+    }
+
+    #[test]
+    fn test_missing() {
+        assert_eq!(
+            messages("[Other]\nName=Steam\n", Kind::Application),
+            ["error: [Desktop Entry] group is missing"]
+        );
+        assert_eq!(
+            messages("[Desktop Entry]\nExec=steam\n", Kind::Application),
+            [
+                "error: required key Name is missing",
+                "error: required key Type is missing"
+            ]
+        );
+        assert_eq!(
+            messages(
+                "[Desktop Entry]\nType=Application\nName=Steam\nActions=a;\n",
+                Kind::Application
+            ),
+            [
+                "error: Exec is required for Type Application",
+                "error: action a has no [Desktop Action a] group"
+            ]
+        );
+        assert!(check(
+            "[Desktop Entry]\nType=Application\nName=Steam\nDBusActivatable=true\n",
+            Kind::Application
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_exec() {
+        let exec = |exec: &str| {
+            messages(
+                &format!("[Desktop Entry]\nType=Application\nName=A\nExec={}\n", exec),
+                Kind::Application,
+            )
+        };
+        assert_eq!(
+            exec("a %x"),
+            ["error: Exec in [Desktop Entry] has the invalid field code %x"]
+        );
+        assert_eq!(
+            exec("a %f %U"),
+            ["error: Exec in [Desktop Entry] has more than one of %f, %F, %u and %U"]
+        );
+        assert_eq!(
+            exec("a \"b %f"),
+            [
+                "error: Exec in [Desktop Entry] has a field code inside a quoted argument",
+                "error: Exec in [Desktop Entry] has an unterminated quote"
+            ]
+        );
+        assert_eq!(
+            exec("a %m"),
+            ["warning: Exec in [Desktop Entry] has the deprecated field code %m"]
+        );
+        assert!(exec("a --percent=100%% %i %c %k").is_empty());
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(
+            messages(
+                "[Desktop Entry]\nType=Application\nName=A\nExec=a\nName=B\nHidden=yes\n",
+                Kind::Application
+            ),
+            [
+                "error: key Name appears more than once in [Desktop Entry]",
+                "error: value \"yes\" of Hidden is not a boolean"
+            ]
+        );
+        assert_eq!(
+            messages("[Desktop Entry]\nNo_Display=1\n", Kind::Application),
+            ["error: line 2: cannot be parsed: invalid key \"No_Display\""]
+        );
+
+        // The same problem after a line was inserted above it.
+        let before = check("[Desktop Entry]\nNo_Display=1\n", Kind::Application);
+        let after = check("[Desktop Entry]\nName=A\nNo_Display=1\n", Kind::Application);
+        assert_ne!(before, after);
+        assert!(before[0].is_same(&after[0]));
+    }
+}
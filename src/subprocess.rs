@@ -0,0 +1,337 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Limits on a generator process. The process is killed as soon as one is
+/// exceeded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Time the process may run.
+    pub timeout: Option<Duration>,
+    /// Bytes the process may write to stdout and stderr each.
+    pub max_output: Option<usize>,
+}
+
+/// A process that could not be run to completion, with what it wrote to
+/// stderr until then.
+#[derive(Debug)]
+pub struct Error {
+    pub error: io::Error,
+    pub stderr: Vec<u8>,
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error {
+            error,
+            stderr: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+/// Run `command` with `envs` added to its environment and `input` on stdin,
+/// collecting its output. Input is fed and output drained in their own
+/// threads, so a process writing before it has read all of its input does
+/// not block on a full pipe.
+///
+/// The process runs in its own process group, which is killed as a whole
+/// once a limit is exceeded. `limits.timeout` covers everything up to the
+/// end of its output, so processes it left running in the background and
+/// holding its stdout or stderr open are killed too.
+pub fn run(
+    command: &[impl AsRef<OsStr>],
+    envs: &[(&str, &OsStr)],
+    input: &[u8],
+    limits: &Limits,
+) -> Result<Output, Error> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let stdout = Reader::spawn(child.stdout.take(), limits.max_output, &exceeded);
+    let stderr = Reader::spawn(child.stderr.take(), limits.max_output, &exceeded);
+
+    let stdin = child.stdin.take().map(|mut stdin| {
+        let input = input.to_vec();
+        thread::spawn(move || match stdin.write_all(&input) {
+            // A process exiting without reading all of its input is fine.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        })
+    });
+
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    let mut interval = Duration::from_millis(1);
+    let mut exit_status = None;
+    let status = loop {
+        if exit_status.is_none() {
+            exit_status = child.try_wait()?;
+        }
+        let done = stdin.as_ref().is_none_or(JoinHandle::is_finished)
+            && stdout.is_finished()
+            && stderr.is_finished();
+        if let Some(status) = exit_status.filter(|_| done) {
+            break status;
+        }
+
+        if exceeded.load(Ordering::Relaxed) {
+            kill(&mut child)?;
+            return Err(Error {
+                error: io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "output exceeded {} bytes, killed",
+                        limits.max_output.unwrap_or_default()
+                    ),
+                ),
+                stderr: stderr.partial(),
+            });
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill(&mut child)?;
+            return Err(Error {
+                error: io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "timed out after {:?}, killed",
+                        limits.timeout.unwrap_or_default()
+                    ),
+                ),
+                stderr: stderr.partial(),
+            });
+        }
+
+        thread::sleep(interval);
+        interval = (interval * 2).min(Duration::from_millis(50));
+    };
+
+    if let Some(stdin) = stdin {
+        stdin
+            .join()
+            .map_err(|_| io::Error::other("Writing input of generator panicked"))??;
+    }
+    let stdout = stdout.join()?;
+    let stderr = stderr.join()?;
+    if exceeded.load(Ordering::Relaxed) {
+        return Err(Error {
+            error: io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "output exceeded {} bytes",
+                    limits.max_output.unwrap_or_default()
+                ),
+            ),
+            stderr,
+        });
+    }
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// A pipe read to the end in another thread, into a buffer which can be
+/// looked at before the end is reached.
+struct Reader {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Reader {
+    /// Start reading `pipe`, stopping and setting `exceeded` once more than
+    /// `limit` bytes arrived.
+    fn spawn(
+        pipe: Option<impl Read + Send + 'static>,
+        limit: Option<usize>,
+        exceeded: &Arc<AtomicBool>,
+    ) -> Reader {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let handle = pipe.map(|mut pipe| {
+            let buffer = Arc::clone(&buffer);
+            let exceeded = Arc::clone(exceeded);
+            thread::spawn(move || {
+                let mut chunk = [0; 8192];
+                loop {
+                    let read = match pipe.read(&mut chunk) {
+                        Ok(0) => return Ok(()),
+                        Ok(read) => read,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let mut buffer = buffer.lock().unwrap();
+                    buffer.extend_from_slice(&chunk[..read]);
+                    if let Some(limit) = limit.filter(|limit| buffer.len() > *limit) {
+                        buffer.truncate(limit);
+                        exceeded.store(true, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                }
+            })
+        });
+        Reader { buffer, handle }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.handle.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// What was read from the pipe of a killed process, waiting a little
+    /// for the pipe to be closed so that nothing written before is lost.
+    fn partial(&self) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while !self.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.buffer.lock().unwrap().clone()
+    }
+
+    fn join(self) -> io::Result<Vec<u8>> {
+        if let Some(handle) = self.handle {
+            handle
+                .join()
+                .map_err(|_| io::Error::other("Reading output of generator panicked"))??;
+        }
+        Ok(std::mem::take(&mut *self.buffer.lock().unwrap()))
+    }
+}
+
+/// Kill the process group of `child`, which has the ID of `child`, and reap
+/// `child`.
+fn kill(child: &mut Child) -> io::Result<()> {
+    // SAFETY: kill does not touch any memory of this process.
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        let e = io::Error::last_os_error();
+        // The whole group has exited already.
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            return Err(e);
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_run() {
+        let output = run(&sh("tr a-z A-Z"), &[], b"foo", &Limits::default()).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"FOO");
+
+        let output = run(
+            &sh("printf %s \"$XDFO_TEST\""),
+            &[("XDFO_TEST", OsStr::new("bar"))],
+            b"",
+            &Limits::default(),
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"bar");
+    }
+
+    #[test]
+    fn test_large_input_and_output() {
+        // Writes all of its output before reading any input, which blocks
+        // on both pipes unless they are served concurrently.
+        let input = vec![b'x'; 1 << 20];
+        let output = run(
+            &sh("head -c 1048576 /dev/zero; cat >/dev/null; echo done >&2"),
+            &[],
+            &input,
+            &Limits::default(),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout.len(), 1 << 20);
+        assert_eq!(output.stderr, b"done\n");
+
+        let output = run(&sh("cat"), &[], &input, &Limits::default()).unwrap();
+        assert_eq!(output.stdout, input);
+    }
+
+    #[test]
+    fn test_timeout_while_not_reading_input() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let err = run(&sh("sleep 10"), &[], &vec![b'x'; 1 << 20], &limits).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let start = Instant::now();
+        let err = run(&sh("sleep 10"), &[], b"", &limits).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_timeout_with_background_process() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(200)),
+            ..Limits::default()
+        };
+        // The background process keeps stdout open after the shell exits.
+        let start = Instant::now();
+        let err = run(&sh("sleep 10 & echo started >&2"), &[], b"", &limits).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.stderr, b"started\n");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Background processes not holding the output do not matter.
+        let output = run(
+            &sh("sleep 10 >/dev/null 2>&1 & echo done"),
+            &[],
+            b"",
+            &limits,
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"done\n");
+    }
+
+    #[test]
+    fn test_max_output() {
+        let limits = Limits {
+            max_output: Some(1024),
+            ..Limits::default()
+        };
+        let err = run(&sh("echo failing >&2; yes"), &[], b"", &limits).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.stderr, b"failing\n");
+
+        let output = run(&sh("printf 1234"), &[], b"", &limits).unwrap();
+        assert_eq!(output.stdout, b"1234");
+    }
+}
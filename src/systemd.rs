@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const PATH_UNIT_NAME: &str = "xdg-desktop-file-override.path";
+pub const SERVICE_UNIT_NAME: &str = "xdg-desktop-file-override.service";
+
+/// Render a path unit starting the service whenever one of `paths` changes.
+pub fn render_path_unit(paths: &[PathBuf]) -> String {
+    let mut unit = String::from(
+        "[Unit]\n\
+         Description=Watch desktop files to override\n\
+         \n\
+         [Path]\n",
+    );
+    for path in paths {
+        unit.push_str(&format!(
+            "PathChanged={}\n",
+            escape(&path.to_string_lossy())
+        ));
+    }
+    unit.push_str(&format!(
+        "Unit={}\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        SERVICE_UNIT_NAME
+    ));
+    unit
+}
+
+/// Render a oneshot service running `executable [options...] generate`.
+pub fn render_service_unit(executable: &Path, options: &[String]) -> String {
+    let mut command = quote(&executable.to_string_lossy());
+    for option in options {
+        command.push(' ');
+        command.push_str(&quote(option));
+    }
+    format!(
+        "[Unit]\n\
+         Description=Override XDG desktop files\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={} generate\n",
+        command
+    )
+}
+
+/// Write both units into `unit_dir`, returning the written paths.
+pub fn install_units(
+    unit_dir: &Path,
+    path_unit: &str,
+    service_unit: &str,
+) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(unit_dir)?;
+
+    let mut written = Vec::new();
+    for (name, content) in [
+        (PATH_UNIT_NAME, path_unit),
+        (SERVICE_UNIT_NAME, service_unit),
+    ] {
+        let path = unit_dir.join(name);
+        fs::write(&path, content)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Remove both units from `unit_dir`, returning the removed paths.
+pub fn uninstall_units(unit_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for name in [PATH_UNIT_NAME, SERVICE_UNIT_NAME] {
+        let path = unit_dir.join(name);
+        match fs::remove_file(&path) {
+            Ok(()) => removed.push(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Escape `%`, which starts a specifier in unit files.
+fn escape(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// Quote one word of a command line in a unit file.
+fn quote(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        escape(value).replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_render_path_unit() {
+        let unit = render_path_unit(&[
+            PathBuf::from("/usr/share/applications"),
+            PathBuf::from("/home/100%/.config/xdg-desktop-file-override/config.yaml"),
+        ]);
+        assert_eq!(
+            unit,
+            "[Unit]\n\
+             Description=Watch desktop files to override\n\
+             \n\
+             [Path]\n\
+             PathChanged=/usr/share/applications\n\
+             PathChanged=/home/100%%/.config/xdg-desktop-file-override/config.yaml\n\
+             Unit=xdg-desktop-file-override.service\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n"
+        );
+    }
+
+    #[test]
+    fn test_render_service_unit() {
+        let unit = render_service_unit(Path::new("/usr/bin/xdg-desktop-file-override"), &[]);
+        assert!(unit.contains("ExecStart=\"/usr/bin/xdg-desktop-file-override\" generate\n"));
+        assert!(unit.contains("Type=oneshot\n"));
+
+        let unit = render_service_unit(
+            Path::new("/usr/bin/xdg-desktop-file-override"),
+            &["--output-dir".to_string(), "/srv/100% \"apps\"".to_string()],
+        );
+        assert!(unit.contains(
+            "ExecStart=\"/usr/bin/xdg-desktop-file-override\" \"--output-dir\" \"/srv/100%% \\\"apps\\\"\" generate\n"
+        ));
+    }
+
+    #[test]
+    fn test_install_and_uninstall_units() {
+        let dir = tempdir().unwrap();
+        let unit_dir = dir.path().join("systemd/user");
+
+        let written = install_units(&unit_dir, "path", "service").unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(
+            fs::read_to_string(unit_dir.join(PATH_UNIT_NAME)).unwrap(),
+            "path"
+        );
+        assert_eq!(
+            fs::read_to_string(unit_dir.join(SERVICE_UNIT_NAME)).unwrap(),
+            "service"
+        );
+
+        assert_eq!(uninstall_units(&unit_dir).unwrap(), written);
+        assert!(uninstall_units(&unit_dir).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,451 @@
+use log::warn;
+use regex::Regex;
+use serde_yaml::Value;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use crate::config::{Condition, Config};
+use crate::migrate;
+
+/// A problem found in a config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.path.display(),
+            self.line,
+            self.column,
+            self.message
+        )
+    }
+}
+
+pub type LoadResult = Result<Config, Vec<Diagnostic>>;
+
+/// Parse, migrate and validate the config file at `path` with `content`.
+pub fn load(path: &Path, content: &str) -> LoadResult {
+    let yaml_error = |e: serde_yaml::Error| {
+        let (line, column) = e
+            .location()
+            .map_or((1, 1), |location| (location.line(), location.column()));
+        vec![Diagnostic {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message: e.to_string(),
+        }]
+    };
+
+    let mut value: Value = serde_yaml::from_str(content).map_err(yaml_error)?;
+    let version = migrate::migrate(&mut value).map_err(|message| {
+        let (line, column) = Locations::new(content).get("version");
+        vec![Diagnostic {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+        }]
+    })?;
+
+    // Deserialize from the text if possible, so errors carry a location.
+    let config: Config = if version == migrate::CONFIG_VERSION {
+        serde_yaml::from_str(content).map_err(yaml_error)?
+    } else {
+        warn!(
+            "{}: config version {} is older than {}, run `migrate-config` to update it",
+            path.display(),
+            version,
+            migrate::CONFIG_VERSION
+        );
+        serde_yaml::from_value(value).map_err(yaml_error)?
+    };
+
+    let mut config = config;
+    let diagnostics = validate(path, content, &config);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    for generator in &mut config.generators {
+        generator.origin = path.to_path_buf();
+    }
+    Ok(config)
+}
+
+/// Check everything in `config` that deserialization does not: regexes and
+/// scripts compile, commands exist and generator names are unique.
+pub fn validate(path: &Path, content: &str, config: &Config) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        path,
+        locations: Locations::new(content),
+        diagnostics: Vec::new(),
+    };
+    let mut names = HashSet::new();
+
+    for (index, generator) in config.generators.iter().enumerate() {
+        let prefix = format!("generators.{}", index);
+
+        if !names.insert(generator.name.as_str()) {
+            validator.report(
+                &format!("{}.name", prefix),
+                format!("duplicate generator name {:?}", generator.name),
+            );
+        }
+
+        validator.check_regex(&format!("{}.filter", prefix), &generator.filter);
+
+        if let Some(condition) = &generator.condition {
+            validator.check_condition(&format!("{}.match", prefix), condition);
+        }
+
+        if generator.disabled {
+            continue;
+        }
+
+        if let Some(timeout) = generator.timeout {
+            if !timeout.is_finite() || timeout <= 0.0 {
+                validator.report(
+                    &format!("{}.timeout", prefix),
+                    format!(
+                        "timeout must be a positive number of seconds, not {}",
+                        timeout
+                    ),
+                );
+            }
+        }
+
+        if let Some(script) = &generator.script {
+            if let Err(e) = crate::script::compile(script) {
+                validator.report(
+                    &format!("{}.script", prefix),
+                    format!("invalid script: {}", e),
+                );
+            }
+        }
+
+        if let Some(command) = generator.command.first() {
+            if !is_command_available(command) {
+                validator.report(
+                    &format!("{}.command.0", prefix),
+                    format!("command {:?} not found in PATH", command),
+                );
+            }
+        }
+    }
+
+    validator.diagnostics
+}
+
+struct Validator<'a> {
+    path: &'a Path,
+    locations: Locations,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator<'_> {
+    fn report(&mut self, key: &str, message: String) {
+        let (line, column) = self.locations.get(key);
+        self.diagnostics.push(Diagnostic {
+            path: self.path.to_path_buf(),
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn check_regex(&mut self, key: &str, pattern: &str) {
+        if let Err(e) = Regex::new(pattern) {
+            self.report(key, format!("invalid regex {:?}: {}", pattern, e));
+        }
+    }
+
+    fn check_condition(&mut self, key: &str, condition: &Condition) {
+        match condition {
+            Condition::All { all: conditions } | Condition::Any { any: conditions } => {
+                let name = if matches!(condition, Condition::All { .. }) {
+                    "all"
+                } else {
+                    "any"
+                };
+                for (index, condition) in conditions.iter().enumerate() {
+                    self.check_condition(&format!("{}.{}.{}", key, name, index), condition);
+                }
+            }
+            Condition::Not { not } => self.check_condition(&format!("{}.not", key), not),
+            Condition::Filename { filename } => {
+                self.check_regex(&format!("{}.filename", key), filename)
+            }
+            Condition::Key(condition) => {
+                if let Some(matches) = &condition.matches {
+                    self.check_regex(&format!("{}.matches", key), matches);
+                }
+            }
+            Condition::Group { .. } | Condition::Origin { .. } => {}
+        }
+    }
+}
+
+fn is_command_available(command: &str) -> bool {
+    if command.contains('/') {
+        return is_executable(Path::new(command));
+    }
+
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| is_executable(&dir.join(command))))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Line and column of every node in a YAML document, keyed by its dotted
+/// path like `generators.0.filter`.
+struct Locations(HashMap<String, (usize, usize)>);
+
+impl Locations {
+    fn new(content: &str) -> Locations {
+        let mut receiver = LocationReceiver {
+            stack: Vec::new(),
+            locations: HashMap::new(),
+        };
+        // The content was already parsed successfully by serde_yaml.
+        let _ = Parser::new(content.chars()).load(&mut receiver, false);
+        Locations(receiver.locations)
+    }
+
+    /// Location of `key`, or of its closest recorded parent.
+    fn get(&self, key: &str) -> (usize, usize) {
+        let mut key = key;
+        loop {
+            if let Some(location) = self.0.get(key) {
+                return *location;
+            }
+            match key.rsplit_once('.') {
+                Some((parent, _)) => key = parent,
+                None => return self.0.get("").copied().unwrap_or((1, 1)),
+            }
+        }
+    }
+}
+
+struct Frame {
+    path: String,
+    kind: FrameKind,
+}
+
+enum FrameKind {
+    /// Holds the key whose value is expected next.
+    Mapping(Option<String>),
+    /// Holds the index of the next element.
+    Sequence(usize),
+}
+
+struct LocationReceiver {
+    stack: Vec<Frame>,
+    locations: HashMap<String, (usize, usize)>,
+}
+
+impl LocationReceiver {
+    /// Called when a node starts. Returns its path, or `None` if the node is
+    /// a mapping key.
+    fn node_start(&mut self, scalar: Option<&str>, mark: Marker) -> Option<String> {
+        let path = match self.stack.last_mut() {
+            None => String::new(),
+            Some(Frame {
+                kind: FrameKind::Mapping(key @ None),
+                ..
+            }) => {
+                *key = Some(scalar.unwrap_or("?").to_string());
+                return None;
+            }
+            Some(Frame {
+                path,
+                kind: FrameKind::Mapping(Some(key)),
+            }) => join(path, key),
+            Some(Frame {
+                path,
+                kind: FrameKind::Sequence(index),
+            }) => join(path, &index.to_string()),
+        };
+        self.locations
+            .insert(path.clone(), (mark.line(), mark.col() + 1));
+        Some(path)
+    }
+
+    fn leaf(&mut self, scalar: Option<&str>, mark: Marker) {
+        if self.node_start(scalar, mark).is_some() {
+            self.node_end();
+        }
+    }
+
+    fn node_end(&mut self) {
+        match self.stack.last_mut() {
+            Some(Frame {
+                kind: FrameKind::Mapping(key),
+                ..
+            }) => *key = None,
+            Some(Frame {
+                kind: FrameKind::Sequence(index),
+                ..
+            }) => *index += 1,
+            None => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for LocationReceiver {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => self.leaf(Some(&value), mark),
+            Event::Alias(_) => self.leaf(None, mark),
+            Event::MappingStart(_) | Event::SequenceStart(_) => {
+                let kind = if matches!(event, Event::MappingStart(_)) {
+                    FrameKind::Mapping(None)
+                } else {
+                    FrameKind::Sequence(0)
+                };
+                // Complex keys are not used by the config, record them
+                // under a placeholder path.
+                let path = self
+                    .node_start(None, mark)
+                    .unwrap_or_else(|| "?".to_string());
+                self.stack.push(Frame { path, kind });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.node_end();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(content: &str) -> Vec<(usize, usize, String)> {
+        match load(Path::new("config.yaml"), content) {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics
+                .into_iter()
+                .map(|d| (d.line, d.column, d.message))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let content = "version: 0.2.0
+generators:
+  - name: a
+    filter: .*
+    command: [sh, -c, cat]
+";
+        assert!(check(content).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let content = "version: 0.2.0
+generators:
+  - name: a
+    filter: '(unclosed'
+  - name: b
+    match:
+      all:
+        - { key: Name }
+        - not: { key: Exec, matches: '[' }
+    command: [definitely-not-a-command-xdfo]
+    timeout: 0
+  - name: a
+    unset: [X]
+  - name: c
+    script: 'entry.set('
+";
+        let diagnostics = check(content);
+        let locations: Vec<_> = diagnostics
+            .iter()
+            .map(|(line, column, _)| (*line, *column))
+            .collect();
+        assert_eq!(
+            locations,
+            [(4, 13), (9, 38), (11, 14), (10, 15), (12, 11), (15, 13)]
+        );
+        assert!(diagnostics[0].2.starts_with("invalid regex \"(unclosed\""));
+        assert!(diagnostics[2]
+            .2
+            .contains("timeout must be a positive number"));
+        assert!(diagnostics[3].2.contains("not found in PATH"));
+        assert!(diagnostics[4].2.contains("duplicate generator name"));
+        assert!(diagnostics[5].2.starts_with("invalid script"));
+    }
+
+    #[test]
+    fn test_version() {
+        assert!(check("version: 0.1.0\ngenerators: []\n").is_empty());
+
+        let diagnostics = check("generators: []\nversion: 9.0.0\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].0, diagnostics[0].1), (2, 10));
+        assert!(diagnostics[0].2.contains("newer"));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let diagnostics = check("version: 0.2.0\ngenerators:\n  - name: [\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].0, 4);
+
+        let diagnostics = check("version: 0.2.0\ngenerators:\n  - filter: .*\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].2.contains("missing field `name`"));
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let diagnostics = check(
+            "version: 0.2.0
+generators:
+  - name: zed
+    filtr: '^zed'
+    on-error: abort-all
+",
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].0, diagnostics[0].1), (4, 5));
+        assert!(diagnostics[0].2.contains("unknown field `filtr`"));
+
+        let diagnostics = check(
+            "version: 0.2.0
+validaton: off
+generators: []
+",
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].2.contains("unknown field `validaton`"));
+    }
+}
@@ -0,0 +1,349 @@
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Changes collected from one burst of events.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// Everything has to be regenerated, e.g. because the config changed or
+    /// events were lost.
+    pub rescan: bool,
+    /// Desktop and directory files created, changed or removed.
+    pub desktop_files: BTreeSet<PathBuf>,
+}
+
+enum ConfigDir {
+    /// Directory holding the config file of this name.
+    File(OsString),
+    /// Drop-in directory, any `*.yaml` file in it is a config file.
+    DropIns,
+}
+
+/// What a directory is watched for.
+enum Role {
+    Source,
+    Config(ConfigDir),
+}
+
+pub struct Watcher {
+    inotify: Inotify,
+    mask: WatchMask,
+    /// Source directories and all their subdirectories.
+    source_dirs: HashMap<WatchDescriptor, PathBuf>,
+    config_dirs: HashMap<WatchDescriptor, ConfigDir>,
+    /// Directories to watch which do not exist yet.
+    missing: Vec<(PathBuf, Role)>,
+    /// Nearest existing ancestors of `missing`, watched for them to appear.
+    ancestors: HashMap<WatchDescriptor, PathBuf>,
+    buffer: Vec<u8>,
+}
+
+impl Watcher {
+    /// Watch the given source directories with their subdirectories,
+    /// the directories holding `config_paths` and the `drop_in_dirs`.
+    /// Directories that do not exist yet are watched for once they appear.
+    pub fn new(
+        source_dirs: &[PathBuf],
+        config_paths: &[PathBuf],
+        drop_in_dirs: &[PathBuf],
+    ) -> io::Result<Watcher> {
+        let mut watcher = Watcher {
+            inotify: Inotify::init()?,
+            mask: WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::DELETE_SELF
+                | WatchMask::MOVE_SELF,
+            source_dirs: HashMap::new(),
+            config_dirs: HashMap::new(),
+            missing: Vec::new(),
+            ancestors: HashMap::new(),
+            buffer: vec![0; 4096],
+        };
+
+        // Watch the directory instead of the file itself, so that editors
+        // replacing the file on save are noticed as well.
+        for config_path in config_paths {
+            let dir = config_path.parent().unwrap_or(Path::new("/"));
+            let file_name = config_path.file_name().unwrap_or_default().to_owned();
+            watcher.add(dir, Role::Config(ConfigDir::File(file_name)))?;
+        }
+        for dir in drop_in_dirs {
+            watcher.add(dir, Role::Config(ConfigDir::DropIns))?;
+        }
+        for dir in source_dirs {
+            watcher.add(dir, Role::Source)?;
+        }
+        Ok(watcher)
+    }
+
+    /// Watch `dir` for `role`, or its nearest existing ancestor until it
+    /// appears. Returns whether `dir` exists.
+    fn add(&mut self, dir: &Path, role: Role) -> io::Result<bool> {
+        if dir.is_dir() {
+            match role {
+                Role::Source => self.add_source_dir(dir)?,
+                Role::Config(config_dir) => {
+                    debug!("Watch {:?}", dir);
+                    let wd = self.inotify.watches().add(dir, self.mask)?;
+                    self.config_dirs.insert(wd, config_dir);
+                }
+            }
+            return Ok(true);
+        }
+
+        let ancestor = dir
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| ancestor.is_dir())
+            .unwrap_or(Path::new("/"));
+        debug!("Watch {:?} until {:?} appears", ancestor, dir);
+        let wd = self.inotify.watches().add(ancestor, self.mask)?;
+        self.ancestors.insert(wd, ancestor.to_path_buf());
+        self.missing.push((dir.to_path_buf(), role));
+        Ok(false)
+    }
+
+    /// Watch the missing directories which appeared since, moving the watch
+    /// of the others closer to them.
+    fn add_appeared(&mut self, changes: &mut Changes) {
+        for (dir, role) in std::mem::take(&mut self.missing) {
+            match self.add(&dir, role) {
+                Ok(true) => {
+                    debug!("{:?} appeared", dir);
+                    changes.rescan = true;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to watch {:?}: {}", dir, e),
+            }
+        }
+    }
+
+    /// Watch `dir` and every directory below it.
+    fn add_source_dir(&mut self, dir: &Path) -> io::Result<()> {
+        debug!("Watch {:?}", dir);
+        let wd = self.inotify.watches().add(dir, self.mask)?;
+        self.source_dirs.insert(wd, dir.to_path_buf());
+
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add_source_dir(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Block until something changes, then keep collecting events until none
+    /// arrived for `debounce`.
+    pub fn wait(&mut self, debounce: Duration) -> io::Result<Changes> {
+        let mut changes = Changes::default();
+
+        let mut pending: Vec<_> = self
+            .inotify
+            .read_events_blocking(&mut self.buffer)?
+            .map(|event| event.to_owned())
+            .collect();
+
+        loop {
+            for event in pending.drain(..) {
+                self.collect(&mut changes, event.wd, event.mask, event.name.as_deref());
+            }
+
+            thread::sleep(debounce);
+            match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => pending.extend(events.map(|event| event.to_owned())),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            if pending.is_empty() {
+                break;
+            }
+        }
+
+        Ok(changes)
+    }
+
+    fn collect(
+        &mut self,
+        changes: &mut Changes,
+        wd: WatchDescriptor,
+        mask: EventMask,
+        name: Option<&std::ffi::OsStr>,
+    ) {
+        if mask.contains(EventMask::Q_OVERFLOW) {
+            debug!("Inotify queue overflowed");
+            changes.rescan = true;
+            return;
+        }
+
+        // An ancestor may be watched for other roles as well.
+        if self.ancestors.contains_key(&wd) {
+            if mask.contains(EventMask::IGNORED) {
+                self.ancestors.remove(&wd);
+                self.add_appeared(changes);
+            } else if mask.contains(EventMask::ISDIR)
+                && mask.intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                self.add_appeared(changes);
+            }
+        }
+
+        if let Some(config_dir) = self.config_dirs.get(&wd) {
+            let is_config_file = match config_dir {
+                ConfigDir::File(file_name) => name == Some(file_name.as_os_str()),
+                ConfigDir::DropIns => {
+                    name.is_some_and(|name| Path::new(name).extension() == Some("yaml".as_ref()))
+                }
+            };
+            if is_config_file {
+                debug!("Config file changed");
+                changes.rescan = true;
+            }
+            return;
+        }
+
+        let Some(dir) = self.source_dirs.get(&wd) else {
+            return;
+        };
+        if mask.contains(EventMask::IGNORED) {
+            self.source_dirs.remove(&wd);
+            return;
+        }
+        if mask.intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF) {
+            debug!("{:?} was removed", dir);
+            changes.rescan = true;
+            return;
+        }
+
+        let Some(name) = name else {
+            return;
+        };
+        let path = dir.join(name);
+        if mask.contains(EventMask::ISDIR) {
+            debug!("Directory {:?} changed", path);
+            changes.rescan = true;
+            if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                if let Err(e) = self.add_source_dir(&path) {
+                    warn!("Failed to watch {:?}: {}", path, e);
+                }
+            }
+            return;
+        }
+        if matches!(
+            path.extension().and_then(|s| s.to_str()),
+            Some("desktop" | "directory")
+        ) {
+            debug!("{:?} changed", path);
+            changes.desktop_files.insert(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wait() {
+        let dir = tempdir().unwrap();
+        let applications = dir.path().join("applications");
+        fs::create_dir_all(&applications).unwrap();
+        let config_path = dir.path().join("config.yaml");
+        let drop_in_dir = dir.path().join("config.d");
+        fs::create_dir_all(&drop_in_dir).unwrap();
+
+        let mut watcher = Watcher::new(
+            std::slice::from_ref(&applications),
+            std::slice::from_ref(&config_path),
+            std::slice::from_ref(&drop_in_dir),
+        )
+        .unwrap();
+
+        fs::write(applications.join("a.desktop"), "").unwrap();
+        fs::write(applications.join("ignored.txt"), "").unwrap();
+        fs::write(applications.join("b.desktop"), "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(!changes.rescan);
+        assert_eq!(
+            changes.desktop_files.into_iter().collect::<Vec<_>>(),
+            [
+                applications.join("a.desktop"),
+                applications.join("b.desktop")
+            ]
+        );
+
+        fs::write(&config_path, "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::write(drop_in_dir.join("10-games.yaml"), "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::create_dir(applications.join("kde4")).unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::write(applications.join("kde4/foo.desktop"), "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(!changes.rescan);
+        assert_eq!(
+            changes.desktop_files.into_iter().collect::<Vec<_>>(),
+            [applications.join("kde4/foo.desktop")]
+        );
+    }
+
+    #[test]
+    fn test_wait_missing_dirs() {
+        let dir = tempdir().unwrap();
+        let applications = dir.path().join("flatpak/exports/share/applications");
+        let config_dir = dir.path().join("config/xdg-desktop-file-override");
+        let config_path = config_dir.join("config.yaml");
+        let drop_in_dir = config_dir.join("config.d");
+
+        let mut watcher = Watcher::new(
+            std::slice::from_ref(&applications),
+            std::slice::from_ref(&config_path),
+            std::slice::from_ref(&drop_in_dir),
+        )
+        .unwrap();
+
+        fs::create_dir_all(&applications).unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::write(applications.join("a.desktop"), "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(!changes.rescan);
+        assert_eq!(
+            changes.desktop_files.into_iter().collect::<Vec<_>>(),
+            [applications.join("a.desktop")]
+        );
+
+        fs::create_dir_all(&config_dir).unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::write(&config_path, "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::create_dir(&drop_in_dir).unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+
+        fs::write(drop_in_dir.join("10-games.yaml"), "").unwrap();
+        let changes = watcher.wait(Duration::from_millis(10)).unwrap();
+        assert!(changes.rescan);
+    }
+}
@@ -1,77 +1,139 @@
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::PathBuf;
 use std::env;
+use std::io;
+use std::path::PathBuf;
 
-pub fn get_config_file(config_file_name: &str) -> io::Result<(PathBuf, Box<dyn Read>)> {
-    // Check XDG_CONFIG_HOME
-    let xdg_config_home = env::var("XDG_CONFIG_HOME").unwrap_or_else(|_| {
-        let home = env::var("HOME").expect("HOME environment variable not set");
-        format!("{}/.config", home)
-    });
-    let config_path = PathBuf::from(xdg_config_home).join(config_file_name);
-    if config_path.exists() {
-        return Ok((config_path.clone(), Box::new(File::open(config_path)?)));
+/// Every existing config file in the XDG config directories, from the
+/// lowest precedence (the last entry of `$XDG_CONFIG_DIRS`) to the highest
+/// (`$XDG_CONFIG_HOME`). In each directory `config_file_name` comes first,
+/// followed by the `*.yaml` files in `drop_in_dir_name` in lexical order.
+pub fn get_config_files(
+    config_file_name: &str,
+    drop_in_dir_name: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = get_config_dirs();
+    // Check default config path
+    let default_config_dir = PathBuf::from("/etc/xdg");
+    if !dirs.contains(&default_config_dir) {
+        dirs.push(default_config_dir);
     }
 
-    // Check XDG_CONFIG_DIRS
-    let xdg_config_dirs = env::var("XDG_CONFIG_DIRS").unwrap_or_else(|_| "/etc/xdg".to_string());
-    let paths: Vec<PathBuf> = env::split_paths(&xdg_config_dirs).collect();
-    for path in paths {
-        let config_path = path.join(config_file_name);
-        if config_path.exists() {
-            return Ok((config_path.clone(), Box::new(File::open(config_path)?)));
-        }
+    let config_files = find_config_files(&dirs, config_file_name, drop_in_dir_name)?;
+    if config_files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Configuration file not found",
+        ));
     }
+    Ok(config_files)
+}
 
-    // Check default config path
-    let default_config_path = PathBuf::from("/etc/xdg").join(config_file_name);
-    if default_config_path.exists() {
-        return Ok((default_config_path.clone(), Box::new(File::open(default_config_path)?)));
+/// Existing config files in `dirs`, which are given from the highest
+/// precedence to the lowest, returned in reverse order.
+fn find_config_files(
+    dirs: &[PathBuf],
+    config_file_name: &str,
+    drop_in_dir_name: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let mut config_files = Vec::new();
+    for dir in dirs.iter().rev() {
+        let config_file = dir.join(config_file_name);
+        if config_file.exists() {
+            config_files.push(config_file);
+        }
+
+        let drop_in_dir = dir.join(drop_in_dir_name);
+        if !drop_in_dir.is_dir() {
+            continue;
+        }
+        let mut drop_ins = Vec::new();
+        for entry in std::fs::read_dir(drop_in_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) == Some("yaml") && path.is_file() {
+                drop_ins.push(path);
+            }
+        }
+        drop_ins.sort();
+        config_files.extend(drop_ins);
     }
+    Ok(config_files)
+}
 
-    Err(io::Error::new(
-        io::ErrorKind::NotFound,
-        "Configuration file not found",
-    ))
+/// `$XDG_CONFIG_HOME`, defaulting to `$HOME/.config`.
+pub fn get_config_home() -> PathBuf {
+    env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_dir().join(".config"))
+}
+
+/// `$XDG_CONFIG_HOME` followed by `$XDG_CONFIG_DIRS`, in order of preference.
+pub fn get_config_dirs() -> Vec<PathBuf> {
+    let xdg_config_dirs = env::var("XDG_CONFIG_DIRS").unwrap_or_else(|_| "/etc/xdg".to_string());
+    let mut dirs = vec![get_config_home()];
+    dirs.extend(env::split_paths(&xdg_config_dirs));
+    dirs
+}
+
+/// `$XDG_DATA_HOME`, defaulting to `$HOME/.local/share`.
+pub fn get_data_home() -> PathBuf {
+    env::var("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_dir().join(".local/share"))
+}
+
+/// `$XDG_STATE_HOME`, defaulting to `$HOME/.local/state`.
+pub fn get_state_home() -> PathBuf {
+    env::var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| home_dir().join(".local/state"))
+}
+
+fn home_dir() -> PathBuf {
+    PathBuf::from(env::var("HOME").expect("HOME environment variable not set"))
 }
 
 #[cfg(test)]
 mod tests {
-    use io::Write;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
 
     use super::*;
 
     #[test]
-    fn test_get_config_file_existing_file() {
+    fn test_get_config_files_existing_file() {
         // Create a temporary directory
         let temp_dir = tempfile::tempdir().expect("Failed to create temporary directory");
 
         // Create a temporary config file
         let config_file_path = temp_dir.path().join("config.txt");
 
-        let mut config_file = File::create(&config_file_path).expect("Failed to create config file");
+        let mut config_file =
+            File::create(&config_file_path).expect("Failed to create config file");
 
         // Write some data to the config file
-        config_file.write_all(b"Hello, World!").expect("Failed to write to config file");
+        config_file
+            .write_all(b"Hello, World!")
+            .expect("Failed to write to config file");
 
         env::set_var("XDG_CONFIG_HOME", temp_dir.path().to_str().unwrap());
-        // Call the get_config_file function
-        let (path, mut reader) = get_config_file("config.txt").expect("Failed to get config file");
+        // Call the get_config_files function
+        let paths = get_config_files("config.txt", "config.d").expect("Failed to get config file");
 
-        // Assert that the returned path is correct
-        assert_eq!(path, config_file_path);
+        // Assert that the file in XDG_CONFIG_HOME has the highest precedence
+        assert_eq!(paths.last().unwrap(), &config_file_path);
 
-        // Read the data from the reader and assert that it matches the written data
+        // Read the data from the file and assert that it matches the written data
         let mut buffer = String::new();
-        reader.read_to_string(&mut buffer).expect("Failed to read from config file");
+        File::open(paths.last().unwrap())
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .expect("Failed to read from config file");
         assert_eq!(buffer, "Hello, World!");
     }
 
     #[test]
-    fn test_get_config_file_nonexistent_file() {
-        // Call the get_config_file function with a non-existent file
-        let result = get_config_file("nonexistent.txt");
+    fn test_get_config_files_nonexistent_file() {
+        // Call the get_config_files function with a non-existent file
+        let result = get_config_files("nonexistent.txt", "nonexistent.d");
 
         // Assert that the function returns an error
         assert!(result.is_err());
@@ -79,4 +141,33 @@ mod tests {
         // Assert that the error kind is NotFound
         assert_eq!(result.err().unwrap().kind(), io::ErrorKind::NotFound);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find_config_files_precedence() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temporary directory");
+        let dirs: Vec<PathBuf> = ["home", "site", "missing", "vendor"]
+            .iter()
+            .map(|name| temp_dir.path().join(name))
+            .collect();
+        for dir in [&dirs[0], &dirs[1], &dirs[3]] {
+            fs::create_dir_all(dir).unwrap();
+            File::create(dir.join("config.yaml")).unwrap();
+        }
+        let drop_in_dir = dirs[1].join("config.d");
+        fs::create_dir_all(&drop_in_dir).unwrap();
+        for name in ["20-b.yaml", "10-a.yaml", "ignored.txt"] {
+            File::create(drop_in_dir.join(name)).unwrap();
+        }
+
+        assert_eq!(
+            find_config_files(&dirs, "config.yaml", "config.d").unwrap(),
+            [
+                dirs[3].join("config.yaml"),
+                dirs[1].join("config.yaml"),
+                drop_in_dir.join("10-a.yaml"),
+                drop_in_dir.join("20-b.yaml"),
+                dirs[0].join("config.yaml"),
+            ]
+        );
+    }
+}